@@ -1,16 +1,22 @@
 //! NySVPN command-line interface.
 //!
 //! Usage:
-//!   nysvpb connect --server <addr> --pubkey <key> --privkey <key> --ip <ip>
-//!   nysvpb disconnect
-//!   nysvpb status
-//!   nysvpb stats
+//!   nysvpb connect [--tunnel <name>] --server <addr> --pubkey <key> --privkey <key> --ip <ip>
+//!   nysvpb disconnect [--tunnel <name>]
+//!   nysvpb status [--tunnel <name>]
+//!   nysvpb stats [--tunnel <name>]
+//!   nysvpb kill-switch on|off
+//!   nysvpb genkey | tee private.key | nysvpb pubkey > public.key
+//!   nysvpb genpsk
 
-use anyhow::Result;
-use clap::{Parser, Subcommand};
+use anyhow::{bail, Context, Result};
+use clap::{Args, Parser, Subcommand, ValueEnum};
 use client::DaemonClient;
-use shared::{TunnelStatus, VpnConfig};
-use std::net::{IpAddr, SocketAddr};
+use nysvpn_core::keys::{PresharedKey, PrivateKey};
+use shared::{TunnelStats, TunnelStatus, VpnConfig, DEFAULT_TUNNEL};
+use std::io::Read;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use zeroize::Zeroizing;
 
 #[derive(Parser)]
 #[command(name = "nysvpb", about = "NySVPN command-line interface", version)]
@@ -22,42 +28,156 @@ struct Cli {
 #[derive(Subcommand)]
 enum Commands {
     /// Connect to a VPN server.
-    Connect {
-        /// Server address (IP:port), e.g. 203.0.113.1:51820
-        #[arg(long)]
-        server: SocketAddr,
+    Connect(Box<ConnectArgs>),
 
-        /// Server WireGuard public key (base64)
-        #[arg(long)]
-        pubkey: String,
+    /// Disconnect from the VPN.
+    Disconnect {
+        /// Name of the tunnel to take down
+        #[arg(long, default_value = DEFAULT_TUNNEL)]
+        tunnel: String,
+    },
 
-        /// Client WireGuard private key (base64)
+    /// Show current connection status.
+    Status {
+        /// Only show this tunnel, default: all of them
         #[arg(long)]
-        privkey: String,
+        tunnel: Option<String>,
+    },
 
-        /// Assigned VPN client IP address
+    /// Show transfer statistics.
+    Stats {
+        /// Only show this tunnel, default: all of them
         #[arg(long)]
-        ip: IpAddr,
+        tunnel: Option<String>,
+    },
 
-        /// DNS servers (comma-separated), default: 1.1.1.1
-        #[arg(long, default_value = "1.1.1.1")]
-        dns: String,
+    /// Block all traffic outside the tunnel while connected.
+    KillSwitch {
+        #[arg(value_enum)]
+        state: Toggle,
     },
 
-    /// Disconnect from the VPN.
-    Disconnect,
+    /// Generate a new private key and print it as base64.
+    Genkey,
 
-    /// Show current connection status.
-    Status,
+    /// Read a private key from stdin and print its public key.
+    Pubkey,
 
-    /// Show transfer statistics.
-    Stats,
+    /// Generate a new pre-shared key and print it as base64.
+    Genpsk,
+}
+
+/// Arguments of `nysvpb connect`.
+#[derive(Args)]
+struct ConnectArgs {
+    /// Name of the tunnel, to run several side by side
+    #[arg(long, default_value = DEFAULT_TUNNEL)]
+    tunnel: String,
+
+    /// Server address (IP:port), e.g. 203.0.113.1:51820
+    #[arg(long)]
+    server: SocketAddr,
+
+    /// Server WireGuard public key (base64)
+    #[arg(long)]
+    pubkey: String,
+
+    /// Client WireGuard private key (base64)
+    #[arg(long)]
+    privkey: String,
+
+    /// Assigned VPN client IP addresses (comma-separated, IPv4 and/or IPv6)
+    #[arg(long, required = true, value_delimiter = ',')]
+    ip: Vec<IpAddr>,
+
+    /// Prefix length of the IPv4 tunnel network, default: 24
+    #[arg(long)]
+    prefix: Option<u8>,
+
+    /// Prefix length of the IPv6 tunnel network, default: 64
+    #[arg(long)]
+    prefix6: Option<u8>,
+
+    /// MTU of the tunnel interface, default: 1420
+    #[arg(long)]
+    mtu: Option<u16>,
+
+    /// Name of the tunnel interface, default: chosen by the OS
+    #[arg(long)]
+    interface: Option<String>,
+
+    /// DNS servers (comma-separated), default: 1.1.1.1 for the default
+    /// tunnel and none for named ones
+    #[arg(long)]
+    dns: Option<String>,
+
+    /// Networks to route through the tunnel (comma-separated CIDRs),
+    /// default: all traffic of each address family in --ip; required for
+    /// named tunnels
+    #[arg(long)]
+    allowed_ips: Option<String>,
+
+    /// Pre-shared key (base64), if the server requires one
+    #[arg(long)]
+    psk: Option<String>,
+
+    /// Longest wait between reconnect attempts in seconds, default: 60
+    #[arg(long)]
+    max_reconnect_delay: Option<u64>,
+
+    /// Send a keepalive after this many idle seconds, e.g. 25 behind NAT;
+    /// default: off
+    #[arg(long)]
+    keepalive: Option<u16>,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Toggle {
+    On,
+    Off,
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
     let cli = Cli::parse();
 
+    // Key commands work offline and must not require a running daemon.
+    match cli.command {
+        Commands::Genkey => println!("{}", *PrivateKey::generate().to_base64()),
+        Commands::Pubkey => println!("{}", read_private_key()?.public_key()),
+        Commands::Genpsk => println!("{}", *PresharedKey::generate().to_base64()),
+        command => run_daemon_command(command).await?,
+    }
+
+    Ok(())
+}
+
+/// DNS server of the default tunnel when `--dns` is not given.
+const DEFAULT_DNS: IpAddr = IpAddr::V4(Ipv4Addr::new(1, 1, 1, 1));
+
+/// Route everything through the tunnel for each address family it carries.
+fn default_allowed_ips(client_ips: &[IpAddr]) -> Vec<String> {
+    let mut allowed = Vec::new();
+    if client_ips.iter().any(IpAddr::is_ipv4) {
+        allowed.push("0.0.0.0/0".to_string());
+    }
+    if client_ips.iter().any(IpAddr::is_ipv6) {
+        allowed.push("::/0".to_string());
+    }
+    allowed
+}
+
+/// Read a base64 private key from stdin, as `wg pubkey` does.
+fn read_private_key() -> Result<PrivateKey> {
+    let mut input = Zeroizing::new(String::new());
+    std::io::stdin()
+        .read_to_string(&mut input)
+        .context("failed to read private key from stdin")?;
+    input.parse().context("invalid private key")
+}
+
+/// Run a command that talks to the daemon.
+async fn run_daemon_command(command: Commands) -> Result<()> {
     let mut client = DaemonClient::connect().await.map_err(|e| {
         anyhow::anyhow!(
             "Cannot connect to daemon at {}: {e}\n\
@@ -66,72 +186,195 @@ async fn main() -> Result<()> {
         )
     })?;
 
-    match cli.command {
-        Commands::Connect {
-            server,
-            pubkey,
-            privkey,
-            ip,
-            dns,
-        } => {
-            let dns_servers: Vec<IpAddr> = dns
-                .split(',')
-                .filter_map(|s| s.trim().parse().ok())
-                .collect();
+    match command {
+        Commands::Connect(args) => {
+            let ConnectArgs {
+                tunnel,
+                server,
+                pubkey,
+                privkey,
+                ip,
+                prefix,
+                prefix6,
+                mtu,
+                interface,
+                dns,
+                allowed_ips,
+                psk,
+                max_reconnect_delay,
+                keepalive,
+            } = *args;
+
+            // Only the default tunnel takes over the resolver and the routing
+            // table unless asked to, so named ones fit alongside it.
+            let named = tunnel != DEFAULT_TUNNEL;
+
+            let dns_servers: Vec<IpAddr> = match dns {
+                Some(list) => list
+                    .split(',')
+                    .filter_map(|s| s.trim().parse().ok())
+                    .collect(),
+                None if named => Vec::new(),
+                None => vec![DEFAULT_DNS],
+            };
+
+            let allowed_ips = match allowed_ips {
+                Some(list) => list
+                    .split(',')
+                    .map(|s| s.trim().to_string())
+                    .filter(|s| !s.is_empty())
+                    .collect(),
+                None if named => bail!("--allowed-ips is required for tunnel {tunnel:?}"),
+                None => default_allowed_ips(&ip),
+            };
 
             let config = VpnConfig {
                 server_addr: server,
                 server_public_key: pubkey,
                 client_private_key: privkey,
-                client_ip: ip,
+                client_ips: ip,
+                prefix_len: prefix,
+                prefix_len_v6: prefix6,
+                mtu,
+                interface_name: interface,
                 dns_servers,
-                allowed_ips: vec!["0.0.0.0/0".to_string()],
+                allowed_ips,
+                preshared_key: psk,
+                reconnect_max_delay_secs: max_reconnect_delay,
+                persistent_keepalive_secs: keepalive,
             };
 
-            client.vpn_connect(config).await?;
+            client.vpn_connect(&tunnel, config).await?;
             println!("Connected to {server}");
         }
 
-        Commands::Disconnect => {
-            client.vpn_disconnect().await?;
+        Commands::Disconnect { tunnel } => {
+            client.vpn_disconnect(&tunnel).await?;
             println!("Disconnected");
         }
 
-        Commands::Status => {
-            let status = client.vpn_status().await?;
-            match status {
-                TunnelStatus::Disconnected => println!("Status: Disconnected"),
-                TunnelStatus::Connecting => println!("Status: Connecting…"),
-                TunnelStatus::Connected { server, since } => {
-                    let elapsed = since
-                        .elapsed()
-                        .map(|d| format_duration(d))
-                        .unwrap_or_else(|_| "unknown".to_string());
-                    println!("Status: Connected to {server} (uptime {elapsed})");
-                }
-                TunnelStatus::Error(e) => println!("Status: Error – {e}"),
+        Commands::Status {
+            tunnel: Some(tunnel),
+        } => {
+            let status = client.vpn_status(&tunnel).await?;
+            println!("Status: {}", describe_status(status));
+        }
+
+        Commands::Status { tunnel: None } => {
+            let tunnels = client.vpn_tunnels().await?;
+            if tunnels.is_empty() {
+                println!("Status: Disconnected");
+            }
+            for info in tunnels {
+                println!("{}: {}", info.name, describe_status(info.status));
             }
         }
 
-        Commands::Stats => {
-            let stats = client.vpn_stats().await?;
-            println!(
-                "↑ Sent:     {} bytes\n↓ Received: {} bytes",
-                stats.bytes_sent, stats.bytes_received
-            );
-            if let Some(ts) = stats.last_handshake {
-                let ago = ts
-                    .elapsed()
-                    .map(|d| format!("{}s ago", d.as_secs()))
-                    .unwrap_or_else(|_| "unknown".to_string());
-                println!("Last handshake: {ago}");
+        Commands::Stats {
+            tunnel: Some(tunnel),
+        } => print_stats(&client.vpn_stats(&tunnel).await?),
+
+        Commands::Stats { tunnel: None } => {
+            let tunnels = client.vpn_tunnels().await?;
+            if tunnels.is_empty() {
+                println!("No tunnels");
+            }
+            for (i, info) in tunnels.iter().enumerate() {
+                if i > 0 {
+                    println!();
+                }
+                println!("{}:", info.name);
+                print_stats(&info.stats);
             }
         }
+
+        Commands::KillSwitch { state } => {
+            let enabled = matches!(state, Toggle::On);
+            client.vpn_set_kill_switch(enabled).await?;
+            println!("Kill switch {}", if enabled { "on" } else { "off" });
+        }
+
+        Commands::Genkey | Commands::Pubkey | Commands::Genpsk => unreachable!("handled in main"),
     }
 
     Ok(())
 }
 
+/// One line describing a tunnel's status, e.g. "Connected to … (uptime …)".
+fn describe_status(status: TunnelStatus) -> String {
+    match status {
+        TunnelStatus::Disconnected => "Disconnected".to_string(),
+        TunnelStatus::Connecting => "Connecting…".to_string(),
+        TunnelStatus::Connected { server, since } => {
+            let elapsed = since
+                .elapsed()
+                .map(format_duration)
+                .unwrap_or_else(|_| "unknown".to_string());
+            format!("Connected to {server} (uptime {elapsed})")
+        }
+        TunnelStatus::Reconnecting {
+            server,
+            attempt,
+            next_attempt,
+        } => {
+            // A time in the past means the attempt is about to start.
+            let next = next_attempt
+                .and_then(|t| t.duration_since(std::time::SystemTime::now()).ok())
+                .map(|d| format!(", next in {}", format_duration(d)))
+                .unwrap_or_default();
+            format!("Reconnecting to {server} (attempt {attempt}{next})…")
+        }
+        TunnelStatus::Error(e) => format!("Error – {e}"),
+    }
+}
+
+fn print_stats(stats: &TunnelStats) {
+    println!(
+        "↑ Sent:     {} bytes in {} packets ({})",
+        stats.bytes_sent,
+        stats.packets_sent,
+        format_rate(stats.bytes_sent_per_sec)
+    );
+    println!(
+        "↓ Received: {} bytes in {} packets ({})",
+        stats.bytes_received,
+        stats.packets_received,
+        format_rate(stats.bytes_received_per_sec)
+    );
+    println!(
+        "Dropped:    {} failed decryption, {} replayed, {} over MTU",
+        stats.decrypt_failures, stats.replay_drops, stats.mtu_drops
+    );
+    if let Some(ts) = stats.last_handshake {
+        let ago = ts
+            .elapsed()
+            .map(|d| format!("{}s ago", d.as_secs()))
+            .unwrap_or_else(|_| "unknown".to_string());
+        let rtt = stats
+            .handshake_rtt_ms
+            .map(|ms| format!(", round trip {ms} ms"))
+            .unwrap_or_default();
+        println!("Last handshake: {ago}{rtt}");
+    }
+}
+
+/// Format a throughput such as `12.3 KB/s`.
+fn format_rate(bytes_per_sec: u64) -> String {
+    const UNITS: [&str; 4] = ["B/s", "KB/s", "MB/s", "GB/s"];
+
+    let mut value = bytes_per_sec as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{bytes_per_sec} B/s")
+    } else {
+        format!("{value:.1} {}", UNITS[unit])
+    }
+}
+
 /// Format a [`std::time::Duration`] as `hh:mm:ss`.
 fn format_duration(d: std::time::Duration) -> String {
     let secs = d.as_secs();
@@ -140,4 +383,3 @@ fn format_duration(d: std::time::Duration) -> String {
     let s = secs % 60;
     format!("{h:02}:{m:02}:{s:02}")
 }
-
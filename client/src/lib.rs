@@ -1,75 +1,274 @@
 //! NySVPN IPC client library.
 //!
-//! Connects to the daemon Unix socket and sends [`VpnCommand`] messages,
-//! returning the deserialized [`VpnResponse`].  Used by both the CLI and
-//! the Tauri GUI backend.
+//! Connects to the daemon Unix socket, agrees on a protocol version and
+//! sends [`VpnCommand`] messages, returning the deserialized [`VpnResponse`].
+//! Used by both the CLI and the Tauri GUI backend.
 
 use anyhow::Result;
-use shared::{TunnelStats, TunnelStatus, VpnCommand, VpnConfig, VpnResponse, SOCKET_PATH};
+use futures::Stream;
+use shared::{
+    ClientMessage, DaemonMessage, TunnelInfo, TunnelStats, TunnelStatus, VpnCommand, VpnConfig,
+    VpnEvent, VpnResponse, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION, SOCKET_PATH,
+};
+use std::path::Path;
 use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::unix::{OwnedReadHalf, OwnedWriteHalf};
 use tokio::net::UnixStream;
 
 /// A connected client session to the NySVPN daemon.
 pub struct DaemonClient {
-    stream: UnixStream,
+    reader: BufReader<OwnedReadHalf>,
+    writer: OwnedWriteHalf,
+    /// Protocol version agreed with the daemon.
+    version: u32,
+    /// Id of the next request.
+    next_id: u64,
 }
 
 impl DaemonClient {
-    /// Connect to the daemon at [`SOCKET_PATH`].
+    /// Connect to the daemon at [`SOCKET_PATH`] and agree on a protocol
+    /// version.
     pub async fn connect() -> Result<Self> {
-        let stream = UnixStream::connect(SOCKET_PATH).await?;
-        Ok(Self { stream })
+        Self::connect_at(Path::new(SOCKET_PATH)).await
+    }
+
+    /// Connect to a daemon listening on `path`.
+    async fn connect_at(path: &Path) -> Result<Self> {
+        let (read_half, writer) = UnixStream::connect(path).await?.into_split();
+        let mut client = Self {
+            reader: BufReader::new(read_half),
+            writer,
+            version: 0,
+            next_id: 1,
+        };
+
+        client
+            .write(&ClientMessage::Hello {
+                min_version: MIN_PROTOCOL_VERSION,
+                max_version: PROTOCOL_VERSION,
+            })
+            .await?;
+        client.version = match client.read().await? {
+            DaemonMessage::Welcome { version } => version,
+            DaemonMessage::Unsupported {
+                min_version,
+                max_version,
+            } => {
+                let upgrade = if max_version < MIN_PROTOCOL_VERSION {
+                    "daemon"
+                } else {
+                    "client"
+                };
+                return Err(anyhow::anyhow!(
+                    "daemon speaks protocol {min_version} to {max_version} but this client \
+                     speaks {MIN_PROTOCOL_VERSION} to {PROTOCOL_VERSION}; please upgrade the \
+                     {upgrade}"
+                ));
+            }
+            // Our hello is well-formed, so only a daemon from before
+            // versioning, which fails to parse it, answers with an error.
+            DaemonMessage::Error(e) => {
+                return Err(anyhow::anyhow!(
+                    "daemon is too old for this client (it predates protocol versioning); \
+                     please upgrade it ({e})"
+                ))
+            }
+            other => return Err(anyhow::anyhow!("unexpected reply to hello: {other:?}")),
+        };
+        Ok(client)
+    }
+
+    /// Protocol version agreed with the daemon.
+    pub fn protocol_version(&self) -> u32 {
+        self.version
     }
 
     /// Send a command and wait for the daemon's response.
-    pub async fn send(&mut self, cmd: VpnCommand) -> Result<VpnResponse> {
-        let mut json = serde_json::to_string(&cmd)?;
+    pub async fn send(&mut self, command: VpnCommand) -> Result<VpnResponse> {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.write(&ClientMessage::Request { id, command }).await?;
+
+        match self.read().await? {
+            DaemonMessage::Response {
+                id: reply_id,
+                response,
+            } if reply_id == id => Ok(response),
+            DaemonMessage::Response { id: reply_id, .. } => Err(anyhow::anyhow!(
+                "daemon answered request {reply_id} while waiting for {id}"
+            )),
+            DaemonMessage::Error(e) => Err(anyhow::anyhow!(e)),
+            other => Err(anyhow::anyhow!("unexpected reply: {other:?}")),
+        }
+    }
+
+    async fn write(&mut self, message: &ClientMessage) -> Result<()> {
+        let mut json = serde_json::to_string(message)?;
         json.push('\n');
+        self.writer.write_all(json.as_bytes()).await?;
+        Ok(())
+    }
 
-        let (read_half, mut write_half) = self.stream.split();
-        write_half.write_all(json.as_bytes()).await?;
+    async fn read(&mut self) -> Result<DaemonMessage> {
+        self.next_message()
+            .await?
+            .ok_or_else(|| anyhow::anyhow!("daemon closed the connection"))
+    }
 
-        let mut reader = BufReader::new(read_half);
+    /// Read one [`DaemonMessage`] line, or `None` once the daemon hangs up.
+    async fn next_message(&mut self) -> Result<Option<DaemonMessage>> {
         let mut line = String::new();
-        reader.read_line(&mut line).await?;
+        if self.reader.read_line(&mut line).await? == 0 {
+            return Ok(None);
+        }
 
-        let response = serde_json::from_str::<VpnResponse>(line.trim())?;
-        Ok(response)
+        Ok(Some(serde_json::from_str(line.trim())?))
     }
 
-    /// Ask the daemon to connect with the given configuration.
-    pub async fn vpn_connect(&mut self, config: VpnConfig) -> Result<()> {
-        match self.send(VpnCommand::Connect(config)).await? {
+    /// Ask the daemon to bring up the tunnel `name` with the given configuration.
+    pub async fn vpn_connect(&mut self, name: &str, config: VpnConfig) -> Result<()> {
+        let cmd = VpnCommand::Connect {
+            name: name.to_string(),
+            config: Box::new(config),
+        };
+        match self.send(cmd).await? {
             VpnResponse::Ok => Ok(()),
             VpnResponse::Error(e) => Err(anyhow::anyhow!(e)),
             other => Err(anyhow::anyhow!("unexpected response: {other:?}")),
         }
     }
 
-    /// Ask the daemon to disconnect.
-    pub async fn vpn_disconnect(&mut self) -> Result<()> {
-        match self.send(VpnCommand::Disconnect).await? {
+    /// Ask the daemon to take down the tunnel `name`.
+    pub async fn vpn_disconnect(&mut self, name: &str) -> Result<()> {
+        let cmd = VpnCommand::Disconnect {
+            name: name.to_string(),
+        };
+        match self.send(cmd).await? {
             VpnResponse::Ok => Ok(()),
             VpnResponse::Error(e) => Err(anyhow::anyhow!(e)),
             other => Err(anyhow::anyhow!("unexpected response: {other:?}")),
         }
     }
 
-    /// Query the current tunnel status.
-    pub async fn vpn_status(&mut self) -> Result<TunnelStatus> {
-        match self.send(VpnCommand::GetStatus).await? {
+    /// Query the current status of the tunnel `name`.
+    pub async fn vpn_status(&mut self, name: &str) -> Result<TunnelStatus> {
+        let cmd = VpnCommand::GetStatus {
+            name: name.to_string(),
+        };
+        match self.send(cmd).await? {
             VpnResponse::Status(s) => Ok(s),
             VpnResponse::Error(e) => Err(anyhow::anyhow!(e)),
             other => Err(anyhow::anyhow!("unexpected response: {other:?}")),
         }
     }
 
-    /// Query transfer statistics.
-    pub async fn vpn_stats(&mut self) -> Result<TunnelStats> {
-        match self.send(VpnCommand::GetStats).await? {
+    /// Turn the daemon's kill switch on or off.
+    pub async fn vpn_set_kill_switch(&mut self, enabled: bool) -> Result<()> {
+        match self.send(VpnCommand::SetKillSwitch(enabled)).await? {
+            VpnResponse::Ok => Ok(()),
+            VpnResponse::Error(e) => Err(anyhow::anyhow!(e)),
+            other => Err(anyhow::anyhow!("unexpected response: {other:?}")),
+        }
+    }
+
+    /// Ask the daemon whether its kill switch is on.
+    pub async fn vpn_kill_switch(&mut self) -> Result<bool> {
+        if self.version < 2 {
+            return Err(anyhow::anyhow!(
+                "daemon is too old to report the kill switch; please upgrade it"
+            ));
+        }
+        match self.send(VpnCommand::GetKillSwitch).await? {
+            VpnResponse::KillSwitch(enabled) => Ok(enabled),
+            VpnResponse::Error(e) => Err(anyhow::anyhow!(e)),
+            other => Err(anyhow::anyhow!("unexpected response: {other:?}")),
+        }
+    }
+
+    /// Query transfer statistics of the tunnel `name`.
+    pub async fn vpn_stats(&mut self, name: &str) -> Result<TunnelStats> {
+        let cmd = VpnCommand::GetStats {
+            name: name.to_string(),
+        };
+        match self.send(cmd).await? {
             VpnResponse::Stats(s) => Ok(s),
             VpnResponse::Error(e) => Err(anyhow::anyhow!(e)),
             other => Err(anyhow::anyhow!("unexpected response: {other:?}")),
         }
     }
+
+    /// Subscribe to the daemon's [`VpnEvent`]s.
+    ///
+    /// The connection is given over to the subscription.  The stream starts
+    /// with the status of every tunnel and ends when the daemon goes away.
+    pub async fn subscribe(mut self) -> Result<impl Stream<Item = Result<VpnEvent>>> {
+        match self.send(VpnCommand::Subscribe).await? {
+            VpnResponse::Ok => {}
+            VpnResponse::Error(e) => return Err(anyhow::anyhow!(e)),
+            other => return Err(anyhow::anyhow!("unexpected response: {other:?}")),
+        }
+
+        // The stream keeps the whole connection, as the daemon stops
+        // streaming once our side is shut down.  It ends after the first
+        // read error.
+        Ok(futures::stream::unfold(Some(self), |client| async move {
+            let mut client = client?;
+            match client.next_message().await {
+                Ok(None) => None,
+                Ok(Some(DaemonMessage::Event(event))) => Some((Ok(event), Some(client))),
+                Ok(Some(DaemonMessage::Error(e))) => Some((Err(anyhow::anyhow!(e)), Some(client))),
+                Ok(Some(other)) => Some((
+                    Err(anyhow::anyhow!("unexpected message: {other:?}")),
+                    Some(client),
+                )),
+                Err(e) => Some((Err(e), None)),
+            }
+        }))
+    }
+
+    /// List every tunnel with its status and statistics.
+    pub async fn vpn_tunnels(&mut self) -> Result<Vec<TunnelInfo>> {
+        match self.send(VpnCommand::ListTunnels).await? {
+            VpnResponse::Tunnels(t) => Ok(t),
+            VpnResponse::Error(e) => Err(anyhow::anyhow!(e)),
+            other => Err(anyhow::anyhow!("unexpected response: {other:?}")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::UnixListener;
+
+    /// What the daemon from before protocol versioning answers to a hello.
+    const UNVERSIONED_REPLY: &str = r#"{"Error":"parse error: unknown variant `Hello`, expected one of `Connect`, `Disconnect`, `GetStatus`, `GetStats` at line 1 column 8"}"#;
+
+    #[tokio::test]
+    async fn a_daemon_from_before_versioning_is_reported_as_too_old() {
+        let path = std::env::temp_dir().join(format!("nysvpb-client-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).unwrap();
+        let daemon = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let (read_half, mut write_half) = stream.into_split();
+            let mut hello = String::new();
+            BufReader::new(read_half)
+                .read_line(&mut hello)
+                .await
+                .unwrap();
+            write_half
+                .write_all(format!("{UNVERSIONED_REPLY}\n").as_bytes())
+                .await
+                .unwrap();
+        });
+
+        let error = DaemonClient::connect_at(&path).await.err().unwrap();
+        daemon.await.unwrap();
+        let _ = std::fs::remove_file(&path);
+        assert!(
+            error.to_string().starts_with("daemon is too old"),
+            "{error}"
+        );
+    }
 }
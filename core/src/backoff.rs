@@ -0,0 +1,104 @@
+//! Jittered exponential backoff for reconnect attempts.
+
+use rand_core::{OsRng, RngCore};
+use std::time::Duration;
+
+/// Nominal delay before the first retry; doubled after every failed attempt.
+pub const INITIAL_DELAY: Duration = Duration::from_secs(1);
+
+/// Cap on the delay when `VpnConfig::reconnect_max_delay_secs` is not set.
+pub const DEFAULT_MAX_DELAY: Duration = Duration::from_secs(60);
+
+/// Delays between retries, doubling from [`INITIAL_DELAY`] up to a cap.
+///
+/// Each delay is randomised to between half and all of its nominal value so
+/// that clients which lost the same server do not all retry in lockstep.
+#[derive(Debug, Clone)]
+pub struct Backoff {
+    max: Duration,
+    attempt: u32,
+}
+
+impl Backoff {
+    pub fn new(max: Duration) -> Self {
+        Self { max, attempt: 0 }
+    }
+
+    /// Number of delays handed out since the last [`reset`](Self::reset).
+    pub fn attempt(&self) -> u32 {
+        self.attempt
+    }
+
+    /// The delay before the next attempt.
+    pub fn next_delay(&mut self) -> Duration {
+        let nominal = INITIAL_DELAY
+            .saturating_mul(1 << self.attempt.min(31))
+            .min(self.max);
+        self.attempt = self.attempt.saturating_add(1);
+
+        let jitter = f64::from(OsRng.next_u32()) / f64::from(u32::MAX);
+        nominal.mul_f64(0.5 + jitter / 2.0)
+    }
+
+    /// Start over from [`INITIAL_DELAY`] after a successful attempt.
+    pub fn reset(&mut self) {
+        self.attempt = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Assert that `delay` is between half and all of `nominal`.
+    fn assert_jittered(delay: Duration, nominal: Duration) {
+        assert!(
+            delay >= nominal / 2 && delay <= nominal,
+            "{delay:?} is not within half of {nominal:?}"
+        );
+    }
+
+    #[test]
+    fn delays_double_up_to_the_cap() {
+        let max = Duration::from_secs(10);
+        let mut backoff = Backoff::new(max);
+        for nominal in [1, 2, 4, 8, 10, 10, 10] {
+            assert_jittered(backoff.next_delay(), Duration::from_secs(nominal));
+        }
+        assert_eq!(backoff.attempt(), 7);
+    }
+
+    #[test]
+    fn delays_are_jittered_within_bounds() {
+        let mut backoff = Backoff::new(DEFAULT_MAX_DELAY);
+        let delays: Vec<_> = (0..64)
+            .map(|_| {
+                backoff.reset();
+                backoff.next_delay()
+            })
+            .collect();
+        for &delay in &delays {
+            assert_jittered(delay, INITIAL_DELAY);
+        }
+        assert!(delays.iter().any(|&delay| delay != delays[0]));
+    }
+
+    #[test]
+    fn many_attempts_do_not_overflow() {
+        let mut backoff = Backoff::new(DEFAULT_MAX_DELAY);
+        backoff.attempt = u32::MAX;
+        assert_jittered(backoff.next_delay(), DEFAULT_MAX_DELAY);
+        assert_eq!(backoff.attempt(), u32::MAX);
+    }
+
+    #[test]
+    fn reset_starts_over() {
+        let mut backoff = Backoff::new(DEFAULT_MAX_DELAY);
+        for _ in 0..5 {
+            backoff.next_delay();
+        }
+        backoff.reset();
+        assert_eq!(backoff.attempt(), 0);
+        assert_jittered(backoff.next_delay(), INITIAL_DELAY);
+    }
+}
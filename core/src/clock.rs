@@ -0,0 +1,21 @@
+//! Wall-clock time for the timestamps a tunnel reports.
+//!
+//! Timeouts and backoff delays run on tokio's timer, which tests control with
+//! `tokio::time::pause`.  The [`Clock`] only supplies the `SystemTime`s shown
+//! in status and stats, such as when the tunnel connected.
+
+use std::time::SystemTime;
+
+pub trait Clock: Send + Sync + 'static {
+    fn now(&self) -> SystemTime;
+}
+
+/// The system's real-time clock, as used by the daemon.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
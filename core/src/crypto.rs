@@ -1,30 +1,475 @@
+//! Transport encryption for an established session.
+//!
+//! Every datagram is sealed with ChaCha20-Poly1305 under the key for its
+//! direction, using the message counter as the nonce.  Failures are reported
+//! as [`CryptoError`] so the packet loop can count and drop bad datagrams
+//! instead of tearing down the tunnel.
+//!
+//! Sessions are short-lived: the initiator starts a new handshake after
+//! [`REKEY_AFTER_TIME`] or [`REKEY_AFTER_MESSAGES`], and a session refuses to
+//! be used past [`REJECT_AFTER_TIME`] / [`REJECT_AFTER_MESSAGES`].  A
+//! [`Keyring`] keeps the previous session around briefly so packets already
+//! in flight when the keys rotate still decrypt.
+//!
+//! Callers pass in the current time, so the timers can be tested without
+//! waiting for them.
+
 use chacha20poly1305::{
-    ChaCha20Poly1305,
-    Key,
-    Nonce,
-    aead::{Aead, KeyInit}
+    aead::{Aead, KeyInit},
+    ChaCha20Poly1305, Key, Nonce,
 };
+use std::time::{Duration, Instant};
+use thiserror::Error;
+
+use crate::handshake::TransportKeys;
+use crate::replay::ReplayWindow;
+use crate::wire::Message;
+
+/// Poly1305 authentication tag appended to every ciphertext.
+const TAG_LEN: usize = 16;
+
+/// Start a new handshake once the current session is this old.
+pub const REKEY_AFTER_TIME: Duration = Duration::from_secs(120);
+
+/// Refuse to use a session that is this old.
+pub const REJECT_AFTER_TIME: Duration = Duration::from_secs(180);
+
+/// Start a new handshake after sending this many messages.
+pub const REKEY_AFTER_MESSAGES: u64 = 1 << 60;
+
+/// Refuse to send or receive more than this many messages under one key,
+/// leaving headroom below `u64::MAX` for the replay window.
+pub const REJECT_AFTER_MESSAGES: u64 = u64::MAX - (1 << 13);
+
+/// How long a replaced session still accepts incoming packets.
+pub const KEEP_PREVIOUS_FOR: Duration = Duration::from_secs(10);
+
+/// Why a transport message could not be sealed or opened.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Error)]
+pub enum CryptoError {
+    /// The ciphertext did not authenticate under the session key.
+    #[error("authentication failed")]
+    AuthFailed,
+    /// The message is too short or too long to be a valid ciphertext.
+    #[error("bad message length: {0} bytes")]
+    BadLength(usize),
+    /// The counter was already seen or has fallen out of the replay window.
+    #[error("replayed counter {0}")]
+    Replay(u64),
+    /// The session is past its time or message limit and must be rekeyed.
+    #[error("session keys expired")]
+    Expired,
+}
+
+/// Keys, counters and replay state for one direction pair of a session.
+pub struct Session {
+    local_index: u32,
+    remote_index: u32,
+    send_cipher: ChaCha20Poly1305,
+    recv_cipher: ChaCha20Poly1305,
+    /// Counter for the next message we send; never reused under `send_cipher`.
+    send_counter: u64,
+    replay: ReplayWindow,
+    created: Instant,
+}
+
+impl Session {
+    /// A session whose keys were agreed at `now`.
+    pub fn new(keys: TransportKeys, now: Instant) -> Self {
+        Self {
+            local_index: keys.local_index,
+            remote_index: keys.remote_index,
+            send_cipher: ChaCha20Poly1305::new(Key::from_slice(&keys.send_key)),
+            recv_cipher: ChaCha20Poly1305::new(Key::from_slice(&keys.recv_key)),
+            send_counter: 0,
+            replay: ReplayWindow::new(),
+            created: now,
+        }
+    }
 
-const KEY_BYTES: [u8; 32] = [1; 32];
+    /// Index the peer addresses our transport messages to.
+    pub fn local_index(&self) -> u32 {
+        self.local_index
+    }
 
-pub fn encrypt(data: &[u8], nonce_bytes: &[u8; 12]) -> Vec<u8> {
+    /// Index we address the peer's transport messages to.
+    pub fn remote_index(&self) -> u32 {
+        self.remote_index
+    }
 
-    let key = Key::from_slice(&KEY_BYTES);
+    /// Whether the initiator should start a new handshake.
+    pub fn needs_rekey(&self, now: Instant) -> bool {
+        now.saturating_duration_since(self.created) >= REKEY_AFTER_TIME
+            || self.send_counter >= REKEY_AFTER_MESSAGES
+    }
 
-    let cipher = ChaCha20Poly1305::new(key);
+    /// Whether the session is past its limits and must no longer be used.
+    pub fn is_expired(&self, now: Instant) -> bool {
+        now.saturating_duration_since(self.created) >= REJECT_AFTER_TIME
+    }
 
-    let nonce = Nonce::from_slice(nonce_bytes);
+    /// Encrypt `plaintext` under the next counter and frame it as a transport
+    /// message ready to send.
+    pub fn seal(&mut self, plaintext: &[u8], now: Instant) -> Result<Vec<u8>, CryptoError> {
+        let counter = self.send_counter;
+        if counter >= REJECT_AFTER_MESSAGES || self.is_expired(now) {
+            return Err(CryptoError::Expired);
+        }
 
-    cipher.encrypt(nonce, data).unwrap()
+        let ciphertext = self
+            .send_cipher
+            .encrypt(Nonce::from_slice(&counter_nonce(counter)), plaintext)
+            .map_err(|_| CryptoError::BadLength(plaintext.len()))?;
+
+        self.send_counter += 1;
+
+        Ok(Message::Transport {
+            receiver: self.remote_index,
+            counter,
+            ciphertext: &ciphertext,
+        }
+        .encode())
+    }
+
+    /// Authenticate and decrypt a transport message received with `counter`.
+    ///
+    /// The replay window is checked before decrypting, so forged traffic is
+    /// dropped cheaply, and only advanced once the message has authenticated.
+    pub fn open(
+        &mut self,
+        counter: u64,
+        ciphertext: &[u8],
+        now: Instant,
+    ) -> Result<Vec<u8>, CryptoError> {
+        if ciphertext.len() < TAG_LEN {
+            return Err(CryptoError::BadLength(ciphertext.len()));
+        }
+        if counter >= REJECT_AFTER_MESSAGES || self.is_expired(now) {
+            return Err(CryptoError::Expired);
+        }
+        if !self.replay.check(counter) {
+            return Err(CryptoError::Replay(counter));
+        }
+
+        let plaintext = self
+            .recv_cipher
+            .decrypt(Nonce::from_slice(&counter_nonce(counter)), ciphertext)
+            .map_err(|_| CryptoError::AuthFailed)?;
+
+        if !self.replay.update(counter) {
+            return Err(CryptoError::Replay(counter));
+        }
+
+        Ok(plaintext)
+    }
 }
 
-pub fn decrypt(data: &[u8], nonce_bytes: &[u8; 12]) -> Vec<u8> {
+/// The sessions of one peer across a key rotation.
+///
+/// `current` is used for sending.  A responder parks a new session in `next`
+/// until the initiator proves it has the keys by sending with them; only then
+/// is it promoted, so a lost handshake response never strands the peer.  The
+/// replaced session stays in `previous` for [`KEEP_PREVIOUS_FOR`] so packets
+/// sent before the rotation can still be opened.
+#[derive(Default)]
+pub struct Keyring {
+    current: Option<Session>,
+    next: Option<Session>,
+    previous: Option<(Session, Instant)>,
+}
 
-    let key = Key::from_slice(&KEY_BYTES);
+impl Keyring {
+    /// Initiator side: start using `session` immediately.
+    pub fn install(&mut self, session: Session, now: Instant) {
+        if let Some(old) = self.current.replace(session) {
+            self.previous = Some((old, now));
+        }
+    }
 
-    let cipher = ChaCha20Poly1305::new(key);
+    /// Responder side: hold `session` until the peer first uses it.
+    pub fn install_next(&mut self, session: Session) {
+        self.next = Some(session);
+    }
 
-    let nonce = Nonce::from_slice(nonce_bytes);
+    /// Session used for sending, if it is still usable.
+    pub fn current_mut(&mut self, now: Instant) -> Option<&mut Session> {
+        self.current.as_mut().filter(|s| !s.is_expired(now))
+    }
 
-    cipher.decrypt(nonce, data).unwrap()
-}
\ No newline at end of file
+    /// Open a transport message addressed to our session `index`, promoting a
+    /// pending session once it authenticates.
+    ///
+    /// Returns `None` if no session in the ring uses `index`.
+    pub fn open(
+        &mut self,
+        index: u32,
+        counter: u64,
+        ciphertext: &[u8],
+        now: Instant,
+    ) -> Option<Result<Vec<u8>, CryptoError>> {
+        if self.next.as_ref().is_some_and(|s| s.local_index == index) {
+            let mut next = self.next.take()?;
+            let result = next.open(counter, ciphertext, now);
+            if result.is_ok() {
+                self.install(next, now);
+            } else {
+                self.next = Some(next);
+            }
+            return Some(result);
+        }
+
+        [
+            self.current.as_mut(),
+            self.previous.as_mut().map(|(s, _)| s),
+        ]
+        .into_iter()
+        .flatten()
+        .find(|s| s.local_index == index)
+        .map(|s| s.open(counter, ciphertext, now))
+    }
+
+    /// Whether any session in the ring uses our local `index`.
+    pub fn has_index(&self, index: u32) -> bool {
+        [
+            self.current.as_ref(),
+            self.next.as_ref(),
+            self.previous.as_ref().map(|(s, _)| s),
+        ]
+        .into_iter()
+        .flatten()
+        .any(|s| s.local_index == index)
+    }
+
+    /// Drop the previous session once its overlap has passed, and any session
+    /// past [`REJECT_AFTER_TIME`].
+    pub fn expire(&mut self, now: Instant) {
+        if self.previous.as_ref().is_some_and(|(s, retired)| {
+            now.saturating_duration_since(*retired) >= KEEP_PREVIOUS_FOR || s.is_expired(now)
+        }) {
+            self.previous = None;
+        }
+        if self.current.as_ref().is_some_and(|s| s.is_expired(now)) {
+            self.current = None;
+        }
+        if self.next.as_ref().is_some_and(|s| s.is_expired(now)) {
+            self.next = None;
+        }
+    }
+}
+
+/// Build the AEAD nonce for message `counter`: four zero bytes followed by the
+/// little-endian counter, as in WireGuard.  Each direction has its own key, so
+/// a counter that never repeats is enough to never reuse a nonce.
+fn counter_nonce(counter: u64) -> [u8; 12] {
+    let mut nonce = [0u8; 12];
+    nonce[4..].copy_from_slice(&counter.to_le_bytes());
+    nonce
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Our end and the peer's end of a session with our index `index`, agreed
+    /// at `now`.  Each index gets its own keys.
+    fn pair(index: u32, now: Instant) -> (Session, Session) {
+        let (a, b) = ([index as u8; 32], [!index as u8; 32]);
+        let ours = Session::new(
+            TransportKeys {
+                local_index: index,
+                remote_index: index + 100,
+                send_key: a,
+                recv_key: b,
+            },
+            now,
+        );
+        let theirs = Session::new(
+            TransportKeys {
+                local_index: index + 100,
+                remote_index: index,
+                send_key: b,
+                recv_key: a,
+            },
+            now,
+        );
+        (ours, theirs)
+    }
+
+    /// The counter and ciphertext of a sealed datagram.
+    fn split(datagram: &[u8]) -> (u64, Vec<u8>) {
+        match Message::parse(datagram) {
+            Some(Message::Transport {
+                counter,
+                ciphertext,
+                ..
+            }) => (counter, ciphertext.to_vec()),
+            other => panic!("not a transport message: {other:?}"),
+        }
+    }
+
+    /// Open `datagram`, sent by the peer, in `ring` under our `index`.
+    fn open(
+        ring: &mut Keyring,
+        index: u32,
+        datagram: &[u8],
+        now: Instant,
+    ) -> Option<Result<Vec<u8>, CryptoError>> {
+        let (counter, ciphertext) = split(datagram);
+        ring.open(index, counter, &ciphertext, now)
+    }
+
+    #[test]
+    fn sealed_messages_open_in_any_order_but_only_once() {
+        let now = Instant::now();
+        let (mut ours, mut theirs) = pair(1, now);
+        let first = split(&theirs.seal(b"first", now).unwrap());
+        let second = split(&theirs.seal(b"second", now).unwrap());
+
+        assert_eq!(ours.open(second.0, &second.1, now).unwrap(), b"second");
+        assert_eq!(ours.open(first.0, &first.1, now).unwrap(), b"first");
+        assert_eq!(
+            ours.open(first.0, &first.1, now),
+            Err(CryptoError::Replay(first.0))
+        );
+    }
+
+    #[test]
+    fn forged_messages_do_not_advance_the_window() {
+        let now = Instant::now();
+        let (mut ours, mut theirs) = pair(1, now);
+        let (counter, ciphertext) = split(&theirs.seal(b"real", now).unwrap());
+
+        // A forgery under the same counter fails to authenticate and must not
+        // burn the counter for the real message.
+        let mut forged = ciphertext.clone();
+        forged[0] ^= 1;
+        assert_eq!(
+            ours.open(counter, &forged, now),
+            Err(CryptoError::AuthFailed)
+        );
+        assert_eq!(ours.open(counter, &ciphertext, now).unwrap(), b"real");
+
+        assert_eq!(
+            ours.open(counter, &[0; 4], now),
+            Err(CryptoError::BadLength(4))
+        );
+    }
+
+    #[test]
+    fn sessions_ask_for_a_rekey_and_then_expire() {
+        let start = Instant::now();
+        let second = Duration::from_secs(1);
+        let (mut ours, mut theirs) = pair(1, start);
+
+        assert!(!ours.needs_rekey(start + REKEY_AFTER_TIME - second));
+        assert!(ours.needs_rekey(start + REKEY_AFTER_TIME));
+        assert!(!ours.is_expired(start + REJECT_AFTER_TIME - second));
+        assert!(ours.is_expired(start + REJECT_AFTER_TIME));
+
+        let late = start + REJECT_AFTER_TIME;
+        let (counter, ciphertext) = split(&theirs.seal(b"late", start).unwrap());
+        assert_eq!(ours.seal(b"late", late), Err(CryptoError::Expired));
+        assert_eq!(
+            ours.open(counter, &ciphertext, late),
+            Err(CryptoError::Expired)
+        );
+
+        ours.send_counter = REKEY_AFTER_MESSAGES;
+        assert!(ours.needs_rekey(start));
+        ours.send_counter = REJECT_AFTER_MESSAGES;
+        assert_eq!(ours.seal(b"one too many", start), Err(CryptoError::Expired));
+    }
+
+    #[test]
+    fn a_pending_session_is_promoted_once_the_peer_uses_it() {
+        let now = Instant::now();
+        let mut ring = Keyring::default();
+        let (old, mut old_peer) = pair(1, now);
+        let (new, mut new_peer) = pair(2, now);
+        ring.install(old, now);
+        ring.install_next(new);
+        assert_eq!(ring.current_mut(now).unwrap().local_index(), 1);
+
+        // A forgery addressed to the pending session does not promote it.
+        let mut forged = new_peer.seal(b"new", now).unwrap();
+        *forged.last_mut().unwrap() ^= 1;
+        assert_eq!(
+            open(&mut ring, 2, &forged, now),
+            Some(Err(CryptoError::AuthFailed))
+        );
+        assert_eq!(ring.current_mut(now).unwrap().local_index(), 1);
+
+        let datagram = new_peer.seal(b"new", now).unwrap();
+        assert_eq!(
+            open(&mut ring, 2, &datagram, now),
+            Some(Ok(b"new".to_vec()))
+        );
+        assert_eq!(ring.current_mut(now).unwrap().local_index(), 2);
+
+        // The replaced session still opens packets sent before the switch.
+        let datagram = old_peer.seal(b"old", now).unwrap();
+        assert_eq!(
+            open(&mut ring, 1, &datagram, now),
+            Some(Ok(b"old".to_vec()))
+        );
+        assert_eq!(open(&mut ring, 3, &datagram, now), None);
+    }
+
+    #[test]
+    fn the_previous_session_is_kept_only_briefly() {
+        let start = Instant::now();
+        let mut ring = Keyring::default();
+        let (old, mut old_peer) = pair(1, start);
+        let (new, _) = pair(2, start);
+        ring.install(old, start);
+        ring.install(new, start);
+
+        let almost = start + KEEP_PREVIOUS_FOR - Duration::from_secs(1);
+        ring.expire(almost);
+        let datagram = old_peer.seal(b"in flight", start).unwrap();
+        assert_eq!(
+            open(&mut ring, 1, &datagram, almost),
+            Some(Ok(b"in flight".to_vec()))
+        );
+
+        ring.expire(start + KEEP_PREVIOUS_FOR);
+        assert!(!ring.has_index(1));
+        let datagram = old_peer.seal(b"too late", start).unwrap();
+        assert_eq!(
+            open(&mut ring, 1, &datagram, start + KEEP_PREVIOUS_FOR),
+            None
+        );
+        assert!(ring.has_index(2));
+    }
+
+    #[test]
+    fn expire_drops_sessions_past_their_lifetime() {
+        let start = Instant::now();
+        let mut ring = Keyring::default();
+        ring.install(pair(1, start).0, start);
+        ring.install(pair(2, start).0, start);
+        ring.install_next(pair(3, start).0);
+        assert!([1, 2, 3].iter().all(|&index| ring.has_index(index)));
+
+        ring.expire(start + REJECT_AFTER_TIME);
+        assert!(![1, 2, 3].iter().any(|&index| ring.has_index(index)));
+        assert!(ring.current_mut(start + REJECT_AFTER_TIME).is_none());
+    }
+
+    #[test]
+    fn has_index_covers_every_slot() {
+        let now = Instant::now();
+        let mut ring = Keyring::default();
+        assert!(!ring.has_index(1));
+
+        ring.install_next(pair(1, now).0);
+        assert!(ring.has_index(1));
+        ring.install(pair(2, now).0, now);
+        ring.install(pair(3, now).0, now);
+        // Pending, current and previous.
+        for index in [1, 3, 2] {
+            assert!(ring.has_index(index), "index {index}");
+        }
+        assert!(!ring.has_index(4));
+    }
+}
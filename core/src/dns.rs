@@ -0,0 +1,425 @@
+//! Pointing the system resolver at `VpnConfig::dns_servers` while connected.
+//!
+//! Queries must go to the tunnel's resolvers, not the local network's, or
+//! they leak which names we look up.  How to change the resolver depends on
+//! the system:
+//!
+//! * Linux with systemd-resolved: set the servers on the TUN link and make it
+//!   the route for every domain (`~.`).
+//! * Linux with `resolvconf`: register an exclusive entry for the TUN.
+//! * Other Linux: move `/etc/resolv.conf` aside to [`BACKUP_PATH`] and write
+//!   our own.
+//! * macOS: override the DNS servers of every enabled network service.
+//!
+//! As with routes, each change is journalled before it is made, [`Dns`]
+//! undoes them when dropped, and [`recover`] undoes whatever a crashed daemon
+//! left behind.  The resolver is system-wide, so only one tunnel at a time may
+//! set DNS servers.
+
+use anyhow::{anyhow, bail, Context, Result};
+use std::fmt;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::str::FromStr;
+
+/// Resolver changes made by the running daemon, one per line.
+fn journal_path() -> PathBuf {
+    Path::new(crate::STATE_DIR).join("dns.journal")
+}
+
+/// Where the original `/etc/resolv.conf` is kept while we replace it.
+pub const BACKUP_PATH: &str = "/etc/resolv.conf.nysvpb";
+
+const RESOLV_CONF: &str = "/etc/resolv.conf";
+
+/// One resolver change, recorded with what is needed to undo it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Change {
+    /// DNS set on a systemd-resolved link.
+    Resolved { interface: String },
+    /// An entry registered with `resolvconf`.
+    Resolvconf { interface: String },
+    /// `/etc/resolv.conf` replaced, original at [`BACKUP_PATH`].
+    ResolvConf,
+    /// A macOS network service's DNS servers overridden; `previous` is empty
+    /// if the service had none set.
+    Service {
+        service: String,
+        previous: Vec<String>,
+    },
+}
+
+impl fmt::Display for Change {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Change::Resolved { interface } => write!(f, "resolved {interface}"),
+            Change::Resolvconf { interface } => write!(f, "resolvconf {interface}"),
+            Change::ResolvConf => write!(f, "file"),
+            // The service name goes last since it may contain spaces.
+            Change::Service { service, previous } if previous.is_empty() => {
+                write!(f, "service - {service}")
+            }
+            Change::Service { service, previous } => {
+                write!(f, "service {} {service}", previous.join(","))
+            }
+        }
+    }
+}
+
+impl FromStr for Change {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (kind, rest) = s.split_once(' ').unwrap_or((s, ""));
+        match kind {
+            "resolved" if !rest.is_empty() => Ok(Change::Resolved {
+                interface: rest.to_string(),
+            }),
+            "resolvconf" if !rest.is_empty() => Ok(Change::Resolvconf {
+                interface: rest.to_string(),
+            }),
+            "file" => Ok(Change::ResolvConf),
+            "service" => {
+                let (previous, service) = rest
+                    .split_once(' ')
+                    .ok_or_else(|| anyhow!("malformed DNS change {s:?}"))?;
+                let previous = match previous {
+                    "-" => Vec::new(),
+                    list => list.split(',').map(str::to_string).collect(),
+                };
+                Ok(Change::Service {
+                    service: service.to_string(),
+                    previous,
+                })
+            }
+            _ => bail!("malformed DNS change {s:?}"),
+        }
+    }
+}
+
+/// Resolver settings applied for a tunnel; restored on drop.
+pub struct Dns {
+    applied: Vec<Change>,
+}
+
+impl Dns {
+    /// Send all DNS queries to `servers` through `interface`.  On error every
+    /// change made so far is undone.
+    pub fn apply(interface: &str, servers: &[IpAddr]) -> Result<Self> {
+        recover()?;
+
+        let mut dns = Self {
+            applied: Vec::new(),
+        };
+        if servers.is_empty() {
+            return Ok(dns);
+        }
+
+        sys::apply(&mut dns, interface, servers)?;
+        Ok(dns)
+    }
+
+    /// Journal `change`, then make it with `apply`.
+    fn record(&mut self, change: Change, apply: impl FnOnce() -> Result<()>) -> Result<()> {
+        let path = journal_path();
+        fs::create_dir_all(crate::STATE_DIR)
+            .with_context(|| format!("failed to create {}", crate::STATE_DIR))?;
+        let mut journal = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .with_context(|| format!("failed to open {}", path.display()))?;
+        writeln!(journal, "{change}")?;
+
+        apply().with_context(|| format!("failed to configure DNS ({change})"))?;
+        self.applied.push(change);
+        Ok(())
+    }
+}
+
+impl Drop for Dns {
+    fn drop(&mut self) {
+        for change in self.applied.drain(..).rev() {
+            if let Err(e) = undo(&change) {
+                eprintln!("failed to restore DNS ({change}): {e}");
+            }
+        }
+        let _ = fs::remove_file(journal_path());
+    }
+}
+
+/// Restore resolver settings left behind by a daemon that exited without
+/// cleaning up.
+pub fn recover() -> Result<()> {
+    let path = journal_path();
+    let journal = match fs::read_to_string(&path) {
+        Ok(journal) => journal,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e).context(format!("failed to read {}", path.display())),
+    };
+
+    for line in journal.lines().rev().filter(|l| !l.trim().is_empty()) {
+        // The change may never have been made if we crashed while making it.
+        match line.parse::<Change>() {
+            Ok(change) => {
+                if undo(&change).is_ok() {
+                    println!("restored DNS ({change})");
+                }
+            }
+            Err(e) => eprintln!("skipping journal entry: {e}"),
+        }
+    }
+
+    fs::remove_file(&path).with_context(|| format!("failed to remove {}", path.display()))
+}
+
+fn undo(change: &Change) -> Result<()> {
+    match change {
+        Change::Resolved { interface } => run("resolvectl", &["revert", interface], None),
+        Change::Resolvconf { interface } => run(
+            "resolvconf",
+            &["-d", &resolvconf_name(interface), "-f"],
+            None,
+        ),
+        Change::ResolvConf => fs::rename(BACKUP_PATH, RESOLV_CONF)
+            .with_context(|| format!("failed to move {BACKUP_PATH} back")),
+        Change::Service { service, previous } => {
+            let mut args = vec!["-setdnsservers", service.as_str()];
+            if previous.is_empty() {
+                args.push("Empty");
+            } else {
+                args.extend(previous.iter().map(String::as_str));
+            }
+            run("networksetup", &args, None)
+        }
+    }
+}
+
+/// Name of our `resolvconf` entry; the `tun.` prefix sorts it ahead of
+/// physical interfaces in Debian's `interface-order`.
+fn resolvconf_name(interface: &str) -> String {
+    format!("tun.{interface}")
+}
+
+/// Run a resolver tool, optionally feeding it `stdin`, and fail with its
+/// stderr on a non-zero exit.
+fn run(program: &str, args: &[&str], stdin: Option<&str>) -> Result<()> {
+    let mut child = Command::new(program)
+        .args(args)
+        .stdin(if stdin.is_some() {
+            Stdio::piped()
+        } else {
+            Stdio::null()
+        })
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()
+        .with_context(|| format!("failed to run {program}"))?;
+
+    if let (Some(input), Some(mut pipe)) = (stdin, child.stdin.take()) {
+        pipe.write_all(input.as_bytes())?;
+    }
+
+    let output = child.wait_with_output()?;
+    if !output.status.success() {
+        bail!(
+            "{program} {} failed: {}",
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+mod sys {
+    use super::*;
+
+    pub fn apply(dns: &mut Dns, interface: &str, servers: &[IpAddr]) -> Result<()> {
+        let servers: Vec<String> = servers.iter().map(IpAddr::to_string).collect();
+
+        if uses_resolved() {
+            let change = Change::Resolved {
+                interface: interface.to_string(),
+            };
+            return dns.record(change, || {
+                let mut args = vec!["dns", interface];
+                args.extend(servers.iter().map(String::as_str));
+                run("resolvectl", &args, None)?;
+                run("resolvectl", &["domain", interface, "~."], None)?;
+                run("resolvectl", &["default-route", interface, "true"], None)
+            });
+        }
+
+        let contents: String = servers
+            .iter()
+            .map(|server| format!("nameserver {server}\n"))
+            .collect();
+
+        if has_command("resolvconf") {
+            let change = Change::Resolvconf {
+                interface: interface.to_string(),
+            };
+            let name = resolvconf_name(interface);
+            return dns.record(change, || {
+                run(
+                    "resolvconf",
+                    &["-a", &name, "-m", "0", "-x"],
+                    Some(&contents),
+                )
+            });
+        }
+
+        dns.record(Change::ResolvConf, || {
+            fs::rename(RESOLV_CONF, BACKUP_PATH)
+                .with_context(|| format!("failed to move {RESOLV_CONF} aside"))?;
+            let header =
+                format!("# Written by nysvpb while connected; original in {BACKUP_PATH}\n");
+            if let Err(e) = fs::write(RESOLV_CONF, header + &contents) {
+                let _ = fs::rename(BACKUP_PATH, RESOLV_CONF);
+                return Err(e).context(format!("failed to write {RESOLV_CONF}"));
+            }
+            Ok(())
+        })
+    }
+
+    /// Whether `/etc/resolv.conf` is managed by systemd-resolved.
+    fn uses_resolved() -> bool {
+        let managed = fs::read_link(RESOLV_CONF)
+            .map(|target| target.to_string_lossy().contains("systemd/resolve"))
+            .unwrap_or(false);
+        managed && has_command("resolvectl")
+    }
+
+    fn has_command(name: &str) -> bool {
+        std::env::var_os("PATH")
+            .map(|path| std::env::split_paths(&path).any(|dir| dir.join(name).is_file()))
+            .unwrap_or(false)
+    }
+}
+
+#[cfg(target_os = "macos")]
+mod sys {
+    use super::*;
+
+    pub fn apply(dns: &mut Dns, _interface: &str, servers: &[IpAddr]) -> Result<()> {
+        let servers: Vec<String> = servers.iter().map(IpAddr::to_string).collect();
+
+        for service in services()? {
+            let previous = current_servers(&service)?;
+            let change = Change::Service {
+                service: service.clone(),
+                previous,
+            };
+            dns.record(change, || {
+                let mut args = vec!["-setdnsservers", service.as_str()];
+                args.extend(servers.iter().map(String::as_str));
+                run("networksetup", &args, None)
+            })?;
+        }
+        Ok(())
+    }
+
+    /// Enabled network services, e.g. "Wi-Fi" and "Thunderbolt Ethernet".
+    fn services() -> Result<Vec<String>> {
+        let output = networksetup(&["-listallnetworkservices"])?;
+        // The first line is a legend; disabled services start with '*'.
+        Ok(output
+            .lines()
+            .skip(1)
+            .filter(|line| !line.is_empty() && !line.starts_with('*'))
+            .map(str::to_string)
+            .collect())
+    }
+
+    /// DNS servers explicitly set on `service`; empty when it uses DHCP's.
+    fn current_servers(service: &str) -> Result<Vec<String>> {
+        let output = networksetup(&["-getdnsservers", service])?;
+        Ok(output
+            .lines()
+            .filter(|line| line.parse::<IpAddr>().is_ok())
+            .map(str::to_string)
+            .collect())
+    }
+
+    fn networksetup(args: &[&str]) -> Result<String> {
+        let output = Command::new("networksetup")
+            .args(args)
+            .output()
+            .context("failed to run networksetup")?;
+        if !output.status.success() {
+            bail!("networksetup {} failed", args.join(" "));
+        }
+        Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+mod sys {
+    use super::*;
+
+    pub fn apply(_dns: &mut Dns, _interface: &str, _servers: &[IpAddr]) -> Result<()> {
+        bail!("DNS configuration is not supported on this platform")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn changes_round_trip_through_the_journal() {
+        let changes = [
+            Change::Resolved {
+                interface: "tun0".to_string(),
+            },
+            Change::Resolvconf {
+                interface: "tun0".to_string(),
+            },
+            Change::ResolvConf,
+            Change::Service {
+                service: "Wi-Fi".to_string(),
+                previous: vec!["192.0.2.53".to_string(), "fd00::53".to_string()],
+            },
+            Change::Service {
+                service: "USB 10/100/1000 LAN".to_string(),
+                previous: vec!["192.0.2.53".to_string()],
+            },
+            Change::Service {
+                service: "Thunderbolt Bridge".to_string(),
+                previous: Vec::new(),
+            },
+        ];
+        for change in changes {
+            let line = change.to_string();
+            assert_eq!(line.parse::<Change>().unwrap(), change, "{line}");
+        }
+    }
+
+    #[test]
+    fn services_without_servers_are_marked_with_a_dash() {
+        let change = Change::Service {
+            service: "Wi-Fi".to_string(),
+            previous: Vec::new(),
+        };
+        assert_eq!(change.to_string(), "service - Wi-Fi");
+        assert_eq!("service - Wi-Fi".parse::<Change>().unwrap(), change);
+    }
+
+    #[test]
+    fn malformed_journal_lines_are_rejected() {
+        for line in [
+            "",
+            "resolved",
+            "resolvconf",
+            "service",
+            "service -",
+            "route tun0",
+        ] {
+            assert!(line.parse::<Change>().is_err(), "{line:?}");
+        }
+    }
+}
@@ -0,0 +1,318 @@
+//! Noise IK handshake used to derive fresh per-session transport keys.
+//!
+//! The client (initiator) knows the server's static public key up front and
+//! proves its own identity in the first message, so a single round trip
+//! yields one ChaCha20-Poly1305 key per direction.  Both sides mix in a fresh
+//! ephemeral key, so every handshake produces new transport keys.
+//!
+//! As in WireGuard, a 32-byte pre-shared key is mixed in at the end of the
+//! response (`IKpsk2`).  Peers without a configured PSK use all zeros, so the
+//! PSK only adds a symmetric secret on top of X25519 as a hedge against a
+//! future quantum attacker recording today's traffic.
+//!
+//! Every initiation carries an encrypted TAI64N timestamp.  A responder that
+//! remembers the newest timestamp per client can reject a replayed
+//! initiation, which would otherwise let anyone who captured one make the
+//! server answer (and point the client's endpoint) wherever they like.
+
+use anyhow::{anyhow, Context, Result};
+use rand_core::{OsRng, RngCore};
+use snow::{Builder, HandshakeState};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const NOISE_PARAMS: &str = "Noise_IKpsk2_25519_ChaChaPoly_BLAKE2s";
+
+/// Position of the pre-shared key token in the `IKpsk2` pattern.
+const PSK_LOCATION: u8 = 2;
+
+/// PSK used when none is configured.
+pub const NO_PRESHARED_KEY: [u8; 32] = [0; 32];
+
+/// Bound into the handshake hash so keys never cross over to another protocol.
+const PROLOGUE: &[u8] = b"nysvpb v1";
+
+/// Largest handshake message we ever produce (IK message 1 with a timestamp is 108 bytes).
+const MAX_HANDSHAKE_LEN: usize = 256;
+
+/// A TAI64N timestamp: seconds since 1970 offset by 2^62, then nanoseconds,
+/// both big-endian.  Later times compare greater as byte strings.
+pub type Timestamp = [u8; 12];
+
+/// Label of 1970-01-01 in TAI64.
+const TAI64_EPOCH: u64 = 1 << 62;
+
+/// Transport keys and session indices produced by a completed handshake.
+pub struct TransportKeys {
+    /// Index the peer puts in front of packets addressed to us.
+    pub local_index: u32,
+    /// Index we put in front of packets addressed to the peer.
+    pub remote_index: u32,
+    pub send_key: [u8; 32],
+    pub recv_key: [u8; 32],
+}
+
+/// Client side of an in-flight handshake.
+pub struct Initiator {
+    state: HandshakeState,
+    local_index: u32,
+}
+
+impl Initiator {
+    /// Start a handshake towards the server identified by `remote_public`,
+    /// sharing `preshared_key` with it (or [`NO_PRESHARED_KEY`]).
+    ///
+    /// Returns the initiator state together with the Noise message to send.
+    pub fn new(
+        local_private: &[u8; 32],
+        remote_public: &[u8; 32],
+        preshared_key: &[u8; 32],
+    ) -> Result<(Self, Vec<u8>)> {
+        let mut state = builder()?
+            .local_private_key(local_private)
+            .remote_public_key(remote_public)
+            .psk(PSK_LOCATION, preshared_key)
+            .build_initiator()?;
+
+        let mut msg = [0u8; MAX_HANDSHAKE_LEN];
+        let len = state.write_message(&timestamp_now(), &mut msg)?;
+
+        let initiator = Self {
+            state,
+            local_index: random_index(),
+        };
+        Ok((initiator, msg[..len].to_vec()))
+    }
+
+    /// Index the server must echo back in its response.
+    pub fn local_index(&self) -> u32 {
+        self.local_index
+    }
+
+    /// Consume the server's response and derive the transport keys.
+    pub fn finish(mut self, sender: u32, payload: &[u8]) -> Result<TransportKeys> {
+        let mut buf = [0u8; MAX_HANDSHAKE_LEN];
+        self.state
+            .read_message(payload, &mut buf)
+            .context("invalid handshake response")?;
+
+        let (send_key, recv_key) = self.state.dangerously_get_raw_split();
+        Ok(TransportKeys {
+            local_index: self.local_index,
+            remote_index: sender,
+            send_key,
+            recv_key,
+        })
+    }
+}
+
+/// Result of answering a client's handshake initiation.
+pub struct Accepted {
+    /// Static public key the client authenticated with.
+    pub remote_public: [u8; 32],
+    /// When the client sent the initiation; must be newer than the last one
+    /// accepted from the same client.
+    pub timestamp: Timestamp,
+    pub keys: TransportKeys,
+    /// Noise response message to send back to the client.
+    pub response: Vec<u8>,
+}
+
+/// Server side: answer the initiation `payload` sent by the client with index `sender`.
+///
+/// Once the client's static key is known, `preshared_key_for` is asked for the
+/// PSK shared with that client; returning `None` rejects the client without
+/// sending a response.  The new session gets a random index for which
+/// `index_in_use` returns `false`, so it never shadows another session.
+pub fn respond(
+    local_private: &[u8; 32],
+    sender: u32,
+    payload: &[u8],
+    preshared_key_for: impl FnOnce(&[u8; 32]) -> Option<[u8; 32]>,
+    index_in_use: impl Fn(u32) -> bool,
+) -> Result<Accepted> {
+    let mut state = builder()?
+        .local_private_key(local_private)
+        .build_responder()?;
+
+    let mut buf = [0u8; MAX_HANDSHAKE_LEN];
+    let len = state
+        .read_message(payload, &mut buf)
+        .context("invalid handshake initiation")?;
+    let timestamp: Timestamp = buf[..len]
+        .try_into()
+        .map_err(|_| anyhow!("handshake initiation carried no timestamp"))?;
+
+    let remote_public: [u8; 32] = state
+        .get_remote_static()
+        .and_then(|s| s.try_into().ok())
+        .ok_or_else(|| anyhow!("handshake initiation carried no static key"))?;
+
+    let preshared_key = preshared_key_for(&remote_public).ok_or_else(|| anyhow!("unknown peer"))?;
+    state.set_psk(PSK_LOCATION.into(), &preshared_key)?;
+
+    let mut msg = [0u8; MAX_HANDSHAKE_LEN];
+    let len = state.write_message(&[], &mut msg)?;
+
+    // The split is ordered initiator→responder first.
+    let (recv_key, send_key) = state.dangerously_get_raw_split();
+
+    Ok(Accepted {
+        remote_public,
+        timestamp,
+        keys: TransportKeys {
+            local_index: unused_index(index_in_use),
+            remote_index: sender,
+            send_key,
+            recv_key,
+        },
+        response: msg[..len].to_vec(),
+    })
+}
+
+fn builder<'a>() -> Result<Builder<'a>> {
+    Ok(Builder::new(NOISE_PARAMS.parse()?).prologue(PROLOGUE))
+}
+
+fn timestamp_now() -> Timestamp {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    let mut timestamp = [0u8; 12];
+    timestamp[..8].copy_from_slice(&(TAI64_EPOCH + now.as_secs()).to_be_bytes());
+    timestamp[8..].copy_from_slice(&now.subsec_nanos().to_be_bytes());
+    timestamp
+}
+
+fn random_index() -> u32 {
+    OsRng.next_u32()
+}
+
+/// Draw random indices until one is not in use, as WireGuard does.
+fn unused_index(in_use: impl Fn(u32) -> bool) -> u32 {
+    loop {
+        let index = random_index();
+        if !in_use(index) {
+            return index;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keys::PrivateKey;
+
+    /// Run a handshake from `client` to the server `server_private`, which
+    /// believes it shares `server_psk` with every client.
+    fn handshake(
+        client: &PrivateKey,
+        server_public: &[u8; 32],
+        client_psk: &[u8; 32],
+        server_private: &PrivateKey,
+        server_psk: [u8; 32],
+    ) -> Result<(TransportKeys, Accepted)> {
+        let (initiator, init) = Initiator::new(client.as_bytes(), server_public, client_psk)?;
+        let accepted = respond(
+            server_private.as_bytes(),
+            initiator.local_index(),
+            &init,
+            |_| Some(server_psk),
+            |_| false,
+        )?;
+        let keys = initiator.finish(accepted.keys.local_index, &accepted.response)?;
+        Ok((keys, accepted))
+    }
+
+    #[test]
+    fn both_sides_derive_the_same_keys() {
+        let (client, server) = (PrivateKey::generate(), PrivateKey::generate());
+        let psk = [7; 32];
+        let (keys, accepted) =
+            handshake(&client, server.public_key().as_bytes(), &psk, &server, psk).unwrap();
+
+        assert_eq!(accepted.remote_public, *client.public_key().as_bytes());
+        assert_eq!(keys.send_key, accepted.keys.recv_key);
+        assert_eq!(keys.recv_key, accepted.keys.send_key);
+        assert_ne!(keys.send_key, keys.recv_key);
+        assert_eq!(keys.remote_index, accepted.keys.local_index);
+        assert_eq!(accepted.keys.remote_index, keys.local_index);
+    }
+
+    #[test]
+    fn every_handshake_derives_new_keys() {
+        let (client, server) = (PrivateKey::generate(), PrivateKey::generate());
+        let server_public = server.public_key();
+        let run = || {
+            handshake(
+                &client,
+                server_public.as_bytes(),
+                &NO_PRESHARED_KEY,
+                &server,
+                NO_PRESHARED_KEY,
+            )
+            .unwrap()
+        };
+        let ((first, first_accepted), (second, second_accepted)) = (run(), run());
+        assert_ne!(first.send_key, second.send_key);
+        assert!(first_accepted.timestamp <= second_accepted.timestamp);
+    }
+
+    #[test]
+    fn the_server_rejects_an_initiation_for_another_key() {
+        let (client, server) = (PrivateKey::generate(), PrivateKey::generate());
+        let elsewhere = PrivateKey::generate().public_key();
+        let result = handshake(
+            &client,
+            elsewhere.as_bytes(),
+            &NO_PRESHARED_KEY,
+            &server,
+            NO_PRESHARED_KEY,
+        );
+        let error = result.err().unwrap();
+        assert_eq!(error.to_string(), "invalid handshake initiation");
+    }
+
+    #[test]
+    fn the_server_rejects_an_unknown_client() {
+        let (client, server) = (PrivateKey::generate(), PrivateKey::generate());
+        let (_, init) = Initiator::new(
+            client.as_bytes(),
+            server.public_key().as_bytes(),
+            &NO_PRESHARED_KEY,
+        )
+        .unwrap();
+        let error = respond(server.as_bytes(), 1, &init, |_| None, |_| false)
+            .err()
+            .unwrap();
+        assert_eq!(error.to_string(), "unknown peer");
+    }
+
+    #[test]
+    fn a_preshared_key_mismatch_fails_at_finish() {
+        let (client, server) = (PrivateKey::generate(), PrivateKey::generate());
+        let (initiator, init) =
+            Initiator::new(client.as_bytes(), server.public_key().as_bytes(), &[1; 32]).unwrap();
+        // The PSK is only mixed in with the response, so the server accepts.
+        let accepted = respond(
+            server.as_bytes(),
+            initiator.local_index(),
+            &init,
+            |_| Some([2; 32]),
+            |_| false,
+        )
+        .unwrap();
+
+        let error = initiator
+            .finish(accepted.keys.local_index, &accepted.response)
+            .err()
+            .unwrap();
+        assert_eq!(error.to_string(), "invalid handshake response");
+    }
+
+    #[test]
+    fn indices_in_use_are_drawn_again() {
+        for _ in 0..32 {
+            assert_eq!(unused_index(|index| index % 2 == 0) % 2, 1);
+        }
+    }
+}
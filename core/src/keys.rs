@@ -0,0 +1,289 @@
+//! X25519 keys and pre-shared keys in the base64 form used by `VpnConfig`.
+//!
+//! Keys travel through configs, IPC and the CLI as 44-character base64
+//! strings, exactly like WireGuard's.  Parsing them here means a malformed
+//! key is rejected with a clear error up front instead of failing somewhere
+//! inside the handshake.  Secret keys are wiped from memory when dropped.
+
+use std::fmt;
+use std::str::FromStr;
+
+use anyhow::Context;
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use rand_core::{OsRng, RngCore};
+use thiserror::Error;
+use x25519_dalek::StaticSecret;
+use zeroize::{Zeroize, ZeroizeOnDrop, Zeroizing};
+
+use shared::VpnConfig;
+
+/// Length in bytes of every key.
+pub const KEY_LEN: usize = 32;
+
+/// Why a base64 key could not be parsed.
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum KeyError {
+    #[error("key is not valid base64")]
+    Base64,
+    #[error("key must be {KEY_LEN} bytes, got {0}")]
+    Length(usize),
+    /// The all-zero public key is a low-order point that yields no shared secret.
+    #[error("key is all zeros")]
+    Zero,
+}
+
+/// An X25519 private key.
+#[derive(Clone, Zeroize, ZeroizeOnDrop)]
+pub struct PrivateKey([u8; KEY_LEN]);
+
+impl PrivateKey {
+    /// Generate a new random private key, clamped like `wg genkey` output.
+    pub fn generate() -> Self {
+        let mut bytes = [0u8; KEY_LEN];
+        OsRng.fill_bytes(&mut bytes);
+        bytes[0] &= 248;
+        bytes[31] &= 127;
+        bytes[31] |= 64;
+        Self(bytes)
+    }
+
+    pub fn from_bytes(bytes: [u8; KEY_LEN]) -> Self {
+        Self(bytes)
+    }
+
+    pub fn as_bytes(&self) -> &[u8; KEY_LEN] {
+        &self.0
+    }
+
+    /// Derive the public key that peers use to identify this key.
+    pub fn public_key(&self) -> PublicKey {
+        let secret = StaticSecret::from(self.0);
+        PublicKey(x25519_dalek::PublicKey::from(&secret).to_bytes())
+    }
+
+    /// Encode as base64; the returned string is wiped when dropped.
+    pub fn to_base64(&self) -> Zeroizing<String> {
+        Zeroizing::new(STANDARD.encode(self.0))
+    }
+}
+
+impl FromStr for PrivateKey {
+    type Err = KeyError;
+
+    fn from_str(s: &str) -> Result<Self, KeyError> {
+        decode(s).map(Self)
+    }
+}
+
+impl fmt::Debug for PrivateKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("PrivateKey(..)")
+    }
+}
+
+/// An X25519 public key, as in `VpnConfig::server_public_key` and
+/// `ServerInfo::public_key`.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct PublicKey([u8; KEY_LEN]);
+
+impl PublicKey {
+    pub fn from_bytes(bytes: [u8; KEY_LEN]) -> Self {
+        Self(bytes)
+    }
+
+    pub fn as_bytes(&self) -> &[u8; KEY_LEN] {
+        &self.0
+    }
+
+    pub fn to_base64(&self) -> String {
+        STANDARD.encode(self.0)
+    }
+}
+
+impl FromStr for PublicKey {
+    type Err = KeyError;
+
+    fn from_str(s: &str) -> Result<Self, KeyError> {
+        let bytes = decode(s)?;
+        if bytes == [0; KEY_LEN] {
+            return Err(KeyError::Zero);
+        }
+        Ok(Self(bytes))
+    }
+}
+
+impl fmt::Display for PublicKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.to_base64())
+    }
+}
+
+impl fmt::Debug for PublicKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "PublicKey({self})")
+    }
+}
+
+/// A symmetric key mixed into the handshake on top of X25519.
+#[derive(Clone, Zeroize, ZeroizeOnDrop)]
+pub struct PresharedKey([u8; KEY_LEN]);
+
+impl PresharedKey {
+    /// Generate a new random pre-shared key, like `wg genpsk`.
+    pub fn generate() -> Self {
+        let mut bytes = [0u8; KEY_LEN];
+        OsRng.fill_bytes(&mut bytes);
+        Self(bytes)
+    }
+
+    pub fn as_bytes(&self) -> &[u8; KEY_LEN] {
+        &self.0
+    }
+
+    /// Encode as base64; the returned string is wiped when dropped.
+    pub fn to_base64(&self) -> Zeroizing<String> {
+        Zeroizing::new(STANDARD.encode(self.0))
+    }
+}
+
+impl FromStr for PresharedKey {
+    type Err = KeyError;
+
+    fn from_str(s: &str) -> Result<Self, KeyError> {
+        decode(s).map(Self)
+    }
+}
+
+impl fmt::Debug for PresharedKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("PresharedKey(..)")
+    }
+}
+
+/// The keys from a [`VpnConfig`], parsed and validated.
+#[derive(Debug, Clone)]
+pub struct ConfigKeys {
+    pub private_key: PrivateKey,
+    pub server_key: PublicKey,
+    pub preshared_key: Option<PresharedKey>,
+}
+
+impl ConfigKeys {
+    /// Parse every key in `config`, naming the field that is invalid.
+    pub fn from_config(config: &VpnConfig) -> anyhow::Result<Self> {
+        Ok(Self {
+            private_key: config
+                .client_private_key
+                .parse()
+                .context("invalid client private key")?,
+            server_key: config
+                .server_public_key
+                .parse()
+                .context("invalid server public key")?,
+            preshared_key: config
+                .preshared_key
+                .as_deref()
+                .map(str::parse)
+                .transpose()
+                .context("invalid pre-shared key")?,
+        })
+    }
+}
+
+/// Decode a base64 key, wiping the intermediate buffer.
+fn decode(b64: &str) -> Result<[u8; KEY_LEN], KeyError> {
+    let bytes = Zeroizing::new(STANDARD.decode(b64.trim()).map_err(|_| KeyError::Base64)?);
+
+    bytes
+        .as_slice()
+        .try_into()
+        .map_err(|_| KeyError::Length(bytes.len()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(s: &str) -> [u8; KEY_LEN] {
+        let bytes: Vec<u8> = (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+            .collect();
+        bytes.try_into().unwrap()
+    }
+
+    #[test]
+    fn public_key_matches_the_rfc_7748_vectors() {
+        let vectors = [
+            (
+                "77076d0a7318a57d3c16c17251b26645df4c2f87ebc0992ab177fba51db92c2a",
+                "8520f0098930a754748b7ddcb43ef75a0dbf3a0d26381af4eba4a98eaa9b4e6a",
+            ),
+            (
+                "5dab087e624a8a4b79e17f8b83800ee66f3bb1292618b6fd1c2f8b27ff88e0eb",
+                "de9edb7d7b7dc1b4d35b61c2ece435373f8343c85b78674dadfc7e146f882b4f",
+            ),
+        ];
+        for (private, public) in vectors {
+            let key = PrivateKey::from_bytes(hex(private));
+            assert_eq!(key.public_key(), PublicKey::from_bytes(hex(public)));
+        }
+    }
+
+    #[test]
+    fn keys_round_trip_through_base64() {
+        let private = PrivateKey::generate();
+        let parsed: PrivateKey = private.to_base64().parse().unwrap();
+        assert_eq!(parsed.as_bytes(), private.as_bytes());
+
+        let public = private.public_key();
+        assert_eq!(public.to_base64().len(), 44);
+        assert_eq!(public.to_base64().parse::<PublicKey>().unwrap(), public);
+        assert_eq!(public.to_string(), public.to_base64());
+
+        let psk = PresharedKey::generate();
+        let parsed: PresharedKey = psk.to_base64().parse().unwrap();
+        assert_eq!(parsed.as_bytes(), psk.as_bytes());
+    }
+
+    #[test]
+    fn surrounding_whitespace_is_ignored() {
+        let public = PrivateKey::generate().public_key();
+        let padded = format!("  {public}\n");
+        assert_eq!(padded.parse::<PublicKey>().unwrap(), public);
+    }
+
+    #[test]
+    fn malformed_keys_are_rejected() {
+        assert_eq!("not base64!".parse::<PublicKey>(), Err(KeyError::Base64));
+        assert_eq!(
+            STANDARD.encode([1; 16]).parse::<PublicKey>(),
+            Err(KeyError::Length(16))
+        );
+        assert_eq!(
+            STANDARD.encode([1; 33]).parse::<PresharedKey>().err(),
+            Some(KeyError::Length(33))
+        );
+        assert_eq!(
+            STANDARD.encode([0; KEY_LEN]).parse::<PublicKey>(),
+            Err(KeyError::Zero)
+        );
+        // Only public keys must not be zero.
+        assert!(STANDARD
+            .encode([0; KEY_LEN])
+            .parse::<PresharedKey>()
+            .is_ok());
+    }
+
+    #[test]
+    fn generated_private_keys_are_clamped() {
+        for _ in 0..16 {
+            let key = PrivateKey::generate();
+            let bytes = key.as_bytes();
+            assert_eq!(bytes[0] & 7, 0);
+            assert_eq!(bytes[31] & 128, 0);
+            assert_eq!(bytes[31] & 64, 64);
+        }
+    }
+}
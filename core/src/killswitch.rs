@@ -0,0 +1,360 @@
+//! Firewall rules that stop traffic from leaving outside the tunnel.
+//!
+//! While the kill switch is active, outgoing packets may only leave through
+//! loopback, the TUN interfaces, or as UDP to the server endpoints (so the
+//! handshakes can reach them).  The physical link also keeps the IPv6
+//! neighbour discovery and DHCP(v6) traffic it needs to stay up, so a server
+//! stays reachable and the lease renews on a long session.  Everything else
+//! is dropped.  One set of rules covers every tunnel, and it belongs to the
+//! connections rather than their packet loops: if a tunnel dies on its own
+//! its TUN interface disappears but the block stays until the user
+//! disconnects or turns the kill switch off.
+//!
+//! All rules live in our own nftables table, iptables chain or pf anchor, so
+//! removing them never touches anyone else's firewall configuration.
+//! Dropping [`KillSwitch`] removes them.  Whether the kill switch is on is
+//! saved in [`STATE_DIR`](crate::STATE_DIR), so that [`recover`] can tell
+//! rules left behind by a daemon that crashed mid-tunnel, which it keeps,
+//! from stale ones, which it removes.
+
+use anyhow::{bail, Context, Result};
+use std::fs;
+use std::io::Write;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+/// What the rules let through besides loopback.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Allowed {
+    /// Server endpoints, reachable over UDP.
+    pub servers: Vec<SocketAddr>,
+    /// TUN interfaces, through which anything may leave.
+    pub interfaces: Vec<String>,
+}
+
+/// Active kill switch rules; removed on drop.
+pub struct KillSwitch {
+    allowed: Allowed,
+}
+
+impl KillSwitch {
+    /// Block all outgoing traffic except loopback and what `allowed` lets
+    /// through.
+    pub fn enable(allowed: Allowed) -> Result<Self> {
+        if let Err(e) = sys::apply(&allowed) {
+            // Clear out whatever part of the rules did get installed.
+            let _ = sys::remove();
+            return Err(e.context("failed to enable kill switch"));
+        }
+        Ok(Self { allowed })
+    }
+
+    /// Replace what the rules let through, e.g. when a tunnel comes up or
+    /// goes away.
+    pub fn update(&mut self, allowed: Allowed) -> Result<()> {
+        if allowed == self.allowed {
+            return Ok(());
+        }
+        sys::apply(&allowed).context("failed to update kill switch")?;
+        self.allowed = allowed;
+        Ok(())
+    }
+}
+
+impl Drop for KillSwitch {
+    fn drop(&mut self) {
+        if let Err(e) = sys::remove() {
+            eprintln!("failed to disable kill switch: {e}");
+        }
+    }
+}
+
+/// Whether the kill switch was left on, as recorded by [`save_setting`].
+pub fn saved_setting() -> bool {
+    setting_path().exists()
+}
+
+/// Record whether the kill switch is on, so a restarted daemon keeps it.
+pub fn save_setting(enabled: bool) -> Result<()> {
+    let path = setting_path();
+    if enabled {
+        fs::create_dir_all(crate::STATE_DIR)
+            .with_context(|| format!("failed to create {}", crate::STATE_DIR))?;
+        fs::write(&path, "on\n").with_context(|| format!("failed to write {}", path.display()))
+    } else {
+        match fs::remove_file(&path) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                Err(e).context(format!("failed to remove {}", path.display()))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+fn setting_path() -> PathBuf {
+    Path::new(crate::STATE_DIR).join("kill-switch")
+}
+
+/// Deal with kill switch rules left behind by a daemon that exited without
+/// cleaning up.
+///
+/// If the kill switch is on, the tunnels they protected dropped unexpectedly,
+/// so the rules are replaced by a block on everything but loopback, which is
+/// returned for the caller to own.  Otherwise they are removed.
+pub fn recover() -> Result<Option<KillSwitch>> {
+    if !sys::installed() {
+        return Ok(None);
+    }
+    if saved_setting() {
+        let kill_switch = KillSwitch::enable(Allowed::default())?;
+        println!("kept the kill switch block from before the restart");
+        return Ok(Some(kill_switch));
+    }
+    sys::remove()?;
+    println!("removed stale kill switch rules");
+    Ok(None)
+}
+
+/// Run a firewall tool, optionally feeding it `stdin`, and return its stdout
+/// and stderr; fails with its stderr on a non-zero exit.
+fn run(program: &str, args: &[&str], stdin: Option<&str>) -> Result<(String, String)> {
+    let mut child = Command::new(program)
+        .args(args)
+        .stdin(if stdin.is_some() {
+            Stdio::piped()
+        } else {
+            Stdio::null()
+        })
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .with_context(|| format!("failed to run {program}"))?;
+
+    if let (Some(input), Some(mut pipe)) = (stdin, child.stdin.take()) {
+        pipe.write_all(input.as_bytes())?;
+    }
+
+    let output = child.wait_with_output()?;
+    let stderr = String::from_utf8_lossy(&output.stderr).into_owned();
+    if !output.status.success() {
+        bail!("{program} {} failed: {}", args.join(" "), stderr.trim());
+    }
+    Ok((String::from_utf8_lossy(&output.stdout).into_owned(), stderr))
+}
+
+#[cfg(target_os = "linux")]
+mod sys {
+    use super::{run, Allowed};
+    use anyhow::Result;
+
+    /// Name of our nftables table and iptables chains.
+    const NAME: &str = "nysvpb";
+
+    /// Where new iptables rules are built before they replace [`NAME`].
+    const STAGING: &str = "nysvpb-new";
+
+    /// Install or replace the rules, preferring nftables when it is available.
+    pub fn apply(allowed: &Allowed) -> Result<()> {
+        if has_nft() {
+            return run("nft", &["-f", "-"], Some(&nft_ruleset(allowed))).map(drop);
+        }
+
+        for (program, ipv4) in [("iptables", true), ("ip6tables", false)] {
+            iptables_apply(program, allowed, ipv4)?;
+        }
+        Ok(())
+    }
+
+    pub fn remove() -> Result<()> {
+        if has_nft() {
+            return run("nft", &["delete", "table", "inet", NAME], None).map(drop);
+        }
+
+        for program in ["iptables", "ip6tables"] {
+            // A staging chain is only left over if an update failed midway.
+            for chain in [STAGING, NAME] {
+                while run(program, &["-D", "OUTPUT", "-j", chain], None).is_ok() {}
+                let _ = run(program, &["-F", chain], None);
+            }
+            let _ = run(program, &["-X", STAGING], None);
+            run(program, &["-X", NAME], None)?;
+        }
+        Ok(())
+    }
+
+    pub fn installed() -> bool {
+        if has_nft() {
+            run("nft", &["list", "table", "inet", NAME], None).is_ok()
+        } else {
+            run("iptables", &["-L", NAME, "-n"], None).is_ok()
+        }
+    }
+
+    fn has_nft() -> bool {
+        run("nft", &["--version"], None).is_ok()
+    }
+
+    /// An atomic replacement of our table: declaring it first makes the
+    /// `delete` succeed even if it does not exist yet.
+    fn nft_ruleset(allowed: &Allowed) -> String {
+        let mut rules = format!(
+            "table inet {NAME}\n\
+             delete table inet {NAME}\n\
+             table inet {NAME} {{\n\
+             \tchain output {{\n\
+             \t\ttype filter hook output priority 0; policy drop;\n\
+             \t\toifname \"lo\" accept\n\
+             \t\ticmpv6 type {{ nd-router-solicit, nd-neighbor-solicit, nd-neighbor-advert }} accept\n\
+             \t\tmeta nfproto ipv4 udp sport 68 udp dport 67 accept\n\
+             \t\tmeta nfproto ipv6 udp sport 546 udp dport 547 accept\n"
+        );
+        for server in &allowed.servers {
+            let family = if server.is_ipv4() { "ip" } else { "ip6" };
+            rules += &format!(
+                "\t\t{family} daddr {} udp dport {} accept\n",
+                server.ip(),
+                server.port()
+            );
+        }
+        for interface in &allowed.interfaces {
+            rules += &format!("\t\toifname \"{interface}\" accept\n");
+        }
+        rules += "\t}\n}\n";
+        rules
+    }
+
+    /// (Re)build our chain for one address family and hook it into OUTPUT.
+    ///
+    /// The rules are built in a separate chain that is hooked in ahead of the
+    /// old one before that is removed, so traffic is never let through while
+    /// they change.
+    fn iptables_apply(program: &str, allowed: &Allowed, ipv4: bool) -> Result<()> {
+        if run(program, &["-N", STAGING], None).is_err() {
+            run(program, &["-F", STAGING], None)?;
+        }
+
+        let append = |rule: &[&str]| {
+            let mut args = vec!["-A", STAGING];
+            args.extend_from_slice(rule);
+            run(program, &args, None).map(drop)
+        };
+
+        append(&["-o", "lo", "-j", "ACCEPT"])?;
+        if ipv4 {
+            append(&[
+                "-p", "udp", "--sport", "68", "--dport", "67", "-j", "ACCEPT",
+            ])?;
+        } else {
+            for kind in [
+                "router-solicitation",
+                "neighbour-solicitation",
+                "neighbour-advertisement",
+            ] {
+                append(&["-p", "ipv6-icmp", "--icmpv6-type", kind, "-j", "ACCEPT"])?;
+            }
+            append(&[
+                "-p", "udp", "--sport", "546", "--dport", "547", "-j", "ACCEPT",
+            ])?;
+        }
+        for interface in &allowed.interfaces {
+            append(&["-o", interface, "-j", "ACCEPT"])?;
+        }
+        for server in allowed.servers.iter().filter(|s| s.is_ipv4() == ipv4) {
+            let ip = server.ip().to_string();
+            let port = server.port().to_string();
+            append(&["-p", "udp", "-d", &ip, "--dport", &port, "-j", "ACCEPT"])?;
+        }
+        append(&["-j", "DROP"])?;
+
+        // Hook in the new chain first, then retire the old one and take its
+        // name.
+        run(program, &["-I", "OUTPUT", "1", "-j", STAGING], None)?;
+        while run(program, &["-D", "OUTPUT", "-j", NAME], None).is_ok() {}
+        if run(program, &["-F", NAME], None).is_ok() {
+            run(program, &["-X", NAME], None)?;
+        }
+        run(program, &["-E", STAGING, NAME], None).map(drop)
+    }
+}
+
+#[cfg(target_os = "macos")]
+mod sys {
+    use super::{run, Allowed};
+    use anyhow::{Context, Result};
+    use std::fs;
+
+    /// Our rules live in a sub-anchor of `com.apple`, which the stock
+    /// `/etc/pf.conf` already evaluates.
+    const ANCHOR: &str = "com.apple/nysvpb";
+
+    /// Reference returned by `pfctl -E`, needed to release our hold on pf.
+    const TOKEN_PATH: &str = "/var/run/nysvpb-pf.token";
+
+    pub fn apply(allowed: &Allowed) -> Result<()> {
+        let mut rules = String::from(
+            "pass out quick on lo0 all\n\
+             pass out quick inet6 proto icmp6 icmp6-type { routersol, neighbrsol, neighbradv }\n\
+             pass out quick inet proto udp from port 68 to port 67\n\
+             pass out quick inet6 proto udp from port 546 to port 547\n",
+        );
+        for server in &allowed.servers {
+            rules += &format!(
+                "pass out quick proto udp to {} port {}\n",
+                server.ip(),
+                server.port()
+            );
+        }
+        for interface in &allowed.interfaces {
+            rules += &format!("pass out quick on {interface} all\n");
+        }
+        rules += "block drop out quick all\n";
+
+        run("pfctl", &["-a", ANCHOR, "-f", "-"], Some(&rules))?;
+
+        if fs::metadata(TOKEN_PATH).is_err() {
+            // pfctl reports "Token : <n>" on stderr.
+            let (_, stderr) = run("pfctl", &["-E"], None)?;
+            let token = stderr
+                .lines()
+                .find_map(|line| line.strip_prefix("Token : "))
+                .context("pfctl -E did not return a token")?;
+            fs::write(TOKEN_PATH, token.trim())?;
+        }
+        Ok(())
+    }
+
+    pub fn remove() -> Result<()> {
+        run("pfctl", &["-a", ANCHOR, "-F", "all"], None)?;
+        if let Ok(token) = fs::read_to_string(TOKEN_PATH) {
+            run("pfctl", &["-X", token.trim()], None)?;
+            fs::remove_file(TOKEN_PATH)?;
+        }
+        Ok(())
+    }
+
+    pub fn installed() -> bool {
+        fs::metadata(TOKEN_PATH).is_ok()
+            || run("pfctl", &["-a", ANCHOR, "-s", "rules"], None)
+                .map(|(rules, _)| !rules.trim().is_empty())
+                .unwrap_or(false)
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+mod sys {
+    use super::Allowed;
+    use anyhow::{bail, Result};
+
+    pub fn apply(_allowed: &Allowed) -> Result<()> {
+        bail!("the kill switch is not supported on this platform")
+    }
+
+    pub fn remove() -> Result<()> {
+        Ok(())
+    }
+
+    pub fn installed() -> bool {
+        false
+    }
+}
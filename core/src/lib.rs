@@ -1,4 +1,25 @@
+pub mod backoff;
+pub mod clock;
 pub mod crypto;
+pub mod dns;
+pub mod handshake;
+pub mod keys;
+pub mod killswitch;
+pub mod platform;
+pub mod replay;
+pub mod route;
+pub mod stats;
+pub mod transport;
 pub mod tun;
 pub mod tunnel;
 pub mod vpn;
+pub mod wire;
+
+/// Where the daemon keeps what must survive a reboot, unlike its files in
+/// `/var/run`.
+#[cfg(target_os = "macos")]
+pub const STATE_DIR: &str = "/var/db/nysvpb";
+/// Where the daemon keeps what must survive a reboot, unlike its files in
+/// `/var/run`.
+#[cfg(not(target_os = "macos"))]
+pub const STATE_DIR: &str = "/var/lib/nysvpb";
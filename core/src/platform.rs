@@ -0,0 +1,136 @@
+//! The system changes a tunnel makes: its TUN device, routes, DNS and the
+//! kill switch.
+//!
+//! [`VpnManager`](crate::vpn::VpnManager) makes them through a [`Platform`],
+//! so tests can run tunnels against an in-memory one without root and without
+//! touching the host.  Each change is undone when the value standing for it
+//! is dropped.
+
+use crate::dns::Dns;
+use crate::killswitch::{Allowed, KillSwitch};
+use crate::route::{Cidr, Routes};
+use anyhow::Result;
+use futures::{Sink, Stream};
+use shared::VpnConfig;
+use std::io;
+use std::net::IpAddr;
+use tokio_util::codec::Framed;
+use tun::{AsyncDevice, TunPacket, TunPacketCodec};
+
+/// The packets of a TUN device, as read by and written to the packet loop.
+pub trait TunDevice:
+    Stream<Item = io::Result<TunPacket>> + Sink<TunPacket, Error = io::Error> + Send + Unpin + 'static
+{
+}
+
+impl<D> TunDevice for D where
+    D: Stream<Item = io::Result<TunPacket>>
+        + Sink<TunPacket, Error = io::Error>
+        + Send
+        + Unpin
+        + 'static
+{
+}
+
+/// Makes, and through the values it returns undoes, a tunnel's changes to the
+/// system.
+pub trait Platform: Send + Sync + 'static {
+    type Tun: TunDevice;
+    /// Routes through a tunnel; removed on drop.
+    type Routes: Send + 'static;
+    /// Resolver settings for a tunnel; restored on drop.
+    type Dns: Send + 'static;
+    /// A block on traffic outside the tunnels; lifted on drop.
+    type KillSwitch: Send + 'static;
+
+    /// Create and bring up the TUN device described by `config`, returning
+    /// it with its interface name.
+    fn create_tun(&self, config: &VpnConfig) -> Result<(Self::Tun, String)>;
+
+    /// Route `allowed_ips` through `interface`, keeping `server` outside it.
+    fn install_routes(
+        &self,
+        interface: &str,
+        server: IpAddr,
+        allowed_ips: &[Cidr],
+    ) -> Result<Self::Routes>;
+
+    /// Send DNS queries to `servers` through `interface`.
+    fn apply_dns(&self, interface: &str, servers: &[IpAddr]) -> Result<Self::Dns>;
+
+    /// Block outgoing traffic except what `allowed` lets through.
+    fn enable_kill_switch(&self, allowed: Allowed) -> Result<Self::KillSwitch>;
+
+    /// Change what an enabled kill switch lets through.
+    fn update_kill_switch(
+        &self,
+        kill_switch: &mut Self::KillSwitch,
+        allowed: Allowed,
+    ) -> Result<()>;
+
+    /// Whether the kill switch was left on, as saved by
+    /// [`save_kill_switch`](Self::save_kill_switch).
+    fn saved_kill_switch(&self) -> bool;
+
+    /// Save whether the kill switch is on, for the next start.
+    fn save_kill_switch(&self, enabled: bool) -> Result<()>;
+
+    /// Undo changes left behind by a daemon that did not shut down cleanly,
+    /// except a kill switch block that should stay, which is returned.
+    fn recover(&self) -> Result<Option<Self::KillSwitch>>;
+}
+
+/// The real system, as changed by the daemon.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Native;
+
+impl Platform for Native {
+    type Tun = Framed<AsyncDevice, TunPacketCodec>;
+    type Routes = Routes;
+    type Dns = Dns;
+    type KillSwitch = KillSwitch;
+
+    fn create_tun(&self, config: &VpnConfig) -> Result<(Self::Tun, String)> {
+        use tun::Device as _;
+
+        let device = crate::tun::create_tun(config)?;
+        let interface = device.get_ref().name()?;
+        Ok((device.into_framed(), interface))
+    }
+
+    fn install_routes(
+        &self,
+        interface: &str,
+        server: IpAddr,
+        allowed_ips: &[Cidr],
+    ) -> Result<Routes> {
+        Routes::install(interface, server, allowed_ips)
+    }
+
+    fn apply_dns(&self, interface: &str, servers: &[IpAddr]) -> Result<Dns> {
+        Dns::apply(interface, servers)
+    }
+
+    fn enable_kill_switch(&self, allowed: Allowed) -> Result<KillSwitch> {
+        KillSwitch::enable(allowed)
+    }
+
+    fn update_kill_switch(&self, kill_switch: &mut KillSwitch, allowed: Allowed) -> Result<()> {
+        kill_switch.update(allowed)
+    }
+
+    fn saved_kill_switch(&self) -> bool {
+        crate::killswitch::saved_setting()
+    }
+
+    fn save_kill_switch(&self, enabled: bool) -> Result<()> {
+        crate::killswitch::save_setting(enabled)
+    }
+
+    fn recover(&self) -> Result<Option<KillSwitch>> {
+        let kill_switch = crate::killswitch::recover()?;
+        crate::dns::recover()?;
+        crate::route::recover()?;
+        Ok(kill_switch)
+    }
+}
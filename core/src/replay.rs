@@ -0,0 +1,139 @@
+//! Sliding-window replay filter for transport counters (RFC 6479).
+//!
+//! The window is a ring of 64-bit blocks.  Advancing the window only clears
+//! the blocks that slide into view, so both checking and updating are O(1)
+//! regardless of how far the counter jumps.
+
+const BLOCK_BITS: u64 = 64;
+const BLOCKS: usize = 32;
+
+/// Number of counters behind the highest one seen that are still accepted.
+/// One block is kept spare so the newest block never overlaps the oldest.
+pub const WINDOW_SIZE: u64 = (BLOCKS as u64 - 1) * BLOCK_BITS;
+
+/// Tracks which counters have been received for one session.
+#[derive(Debug, Clone)]
+pub struct ReplayWindow {
+    /// Highest counter accepted so far.
+    last: u64,
+    bitmap: [u64; BLOCKS],
+}
+
+impl Default for ReplayWindow {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ReplayWindow {
+    pub fn new() -> Self {
+        Self {
+            last: 0,
+            bitmap: [0; BLOCKS],
+        }
+    }
+
+    /// Whether `counter` could still be accepted.
+    ///
+    /// Call this before decrypting so forged packets are dropped cheaply, and
+    /// [`ReplayWindow::update`] only once the packet has authenticated.
+    pub fn check(&self, counter: u64) -> bool {
+        if counter > self.last {
+            return true;
+        }
+        if self.last - counter > WINDOW_SIZE {
+            return false;
+        }
+        let (block, bit) = Self::position(counter);
+        self.bitmap[block] & bit == 0
+    }
+
+    /// Record `counter` as received. Returns `false` if it is a duplicate or
+    /// has fallen out of the window, in which case the packet must be dropped.
+    pub fn update(&mut self, counter: u64) -> bool {
+        if counter > self.last {
+            let current = self.last / BLOCK_BITS;
+            let target = counter / BLOCK_BITS;
+            let advance = (target - current).min(BLOCKS as u64);
+
+            for i in 1..=advance {
+                self.bitmap[((current + i) % BLOCKS as u64) as usize] = 0;
+            }
+            self.last = counter;
+        } else if self.last - counter > WINDOW_SIZE {
+            return false;
+        }
+
+        let (block, bit) = Self::position(counter);
+        if self.bitmap[block] & bit != 0 {
+            return false;
+        }
+        self.bitmap[block] |= bit;
+        true
+    }
+
+    fn position(counter: u64) -> (usize, u64) {
+        let block = ((counter / BLOCK_BITS) % BLOCKS as u64) as usize;
+        (block, 1 << (counter % BLOCK_BITS))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn duplicates_are_rejected() {
+        let mut window = ReplayWindow::new();
+        assert!(window.update(0));
+        assert!(window.update(1));
+        assert!(!window.check(1));
+        assert!(!window.update(1));
+        assert!(!window.update(0));
+    }
+
+    #[test]
+    fn counters_behind_the_window_are_rejected() {
+        let mut window = ReplayWindow::new();
+        assert!(window.update(WINDOW_SIZE + 10));
+
+        // The oldest counter still inside the window is accepted once...
+        assert!(window.check(10));
+        assert!(window.update(10));
+        assert!(!window.update(10));
+        // ...and anything older is not, even if never seen.
+        assert!(!window.check(9));
+        assert!(!window.update(9));
+    }
+
+    #[test]
+    fn a_jump_past_the_whole_ring_clears_it() {
+        let mut window = ReplayWindow::new();
+        for counter in 0..100 {
+            assert!(window.update(counter));
+        }
+
+        let far = 99 + BLOCKS as u64 * BLOCK_BITS + 7;
+        assert!(window.update(far));
+        // Counters that land on the same bits as old ones are new again.
+        for counter in far - WINDOW_SIZE..far {
+            assert!(window.check(counter), "counter {counter}");
+        }
+        assert!(!window.check(99));
+        assert!(!window.update(far));
+    }
+
+    #[test]
+    fn out_of_order_counters_within_the_window_are_accepted_once() {
+        let mut window = ReplayWindow::new();
+        for counter in [5, 3, 200, 4, 150, 0, 199] {
+            assert!(window.update(counter), "counter {counter}");
+        }
+        for counter in [5, 3, 200, 4, 150, 0, 199] {
+            assert!(!window.update(counter), "counter {counter} replayed");
+        }
+        // Gaps stay open.
+        assert!(window.update(1));
+        assert!(window.update(198));
+    }
+}
@@ -0,0 +1,606 @@
+//! Routing table changes for an active tunnel.
+//!
+//! On connect every CIDR in `VpnConfig::allowed_ips` is routed through the
+//! TUN interface.  A catch-all `/0` is installed as two `/1` halves, which
+//! win over the existing default route by being more specific without
+//! replacing it.  If the server itself falls inside an allowed range, a host
+//! route keeps its traffic on the original gateway so the tunnel does not
+//! try to carry its own packets.
+//!
+//! Routes are only ever added, never changed, so restoring the previous table
+//! means deleting exactly what we added.  Each route is written to its
+//! tunnel's journal in [`JOURNAL_DIR`] before it is installed; [`Routes`]
+//! removes them again when dropped, and [`recover`] removes whatever a
+//! crashed daemon left behind.
+
+use anyhow::{anyhow, bail, Context, Result};
+use std::fmt;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::str::FromStr;
+
+/// Where the running daemon journals the routes it installs, one file per
+/// tunnel interface and one route per line.
+pub const JOURNAL_DIR: &str = "/var/run";
+
+const JOURNAL_PREFIX: &str = "nysvpb-routes.";
+const JOURNAL_SUFFIX: &str = ".journal";
+
+/// The route journal for the tunnel on `interface`.
+fn journal_path(interface: &str) -> PathBuf {
+    Path::new(JOURNAL_DIR).join(format!("{JOURNAL_PREFIX}{interface}{JOURNAL_SUFFIX}"))
+}
+
+/// An IP network such as `10.0.0.0/8`, with the host bits cleared.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    pub fn new(addr: IpAddr, prefix: u8) -> Result<Self> {
+        let max = max_prefix(addr);
+        if prefix > max {
+            bail!("prefix /{prefix} is longer than {max} bits");
+        }
+        Ok(Self {
+            addr: mask(addr, prefix),
+            prefix,
+        })
+    }
+
+    /// A single-address network.
+    pub fn host(addr: IpAddr) -> Self {
+        Self {
+            addr,
+            prefix: max_prefix(addr),
+        }
+    }
+
+    pub fn addr(&self) -> IpAddr {
+        self.addr
+    }
+
+    pub fn prefix(&self) -> u8 {
+        self.prefix
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        self.addr.is_ipv4() == ip.is_ipv4() && mask(ip, self.prefix) == self.addr
+    }
+
+    /// Whether some address lies in both networks.
+    pub fn overlaps(&self, other: &Cidr) -> bool {
+        self.contains(other.addr) || other.contains(self.addr)
+    }
+
+    /// Split a `/0` into its two `/1` halves; other networks are returned as is.
+    fn split_default(self) -> Vec<Cidr> {
+        if self.prefix != 0 {
+            return vec![self];
+        }
+        let upper = match self.addr {
+            IpAddr::V4(_) => IpAddr::V4(Ipv4Addr::new(128, 0, 0, 0)),
+            IpAddr::V6(_) => IpAddr::V6(Ipv6Addr::new(0x8000, 0, 0, 0, 0, 0, 0, 0)),
+        };
+        vec![
+            Self {
+                addr: self.addr,
+                prefix: 1,
+            },
+            Self {
+                addr: upper,
+                prefix: 1,
+            },
+        ]
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let s = s.trim();
+        match s.split_once('/') {
+            Some((addr, prefix)) => {
+                let addr: IpAddr = addr.parse().with_context(|| format!("invalid CIDR {s}"))?;
+                let prefix = prefix
+                    .parse()
+                    .with_context(|| format!("invalid CIDR {s}"))?;
+                Self::new(addr, prefix).with_context(|| format!("invalid CIDR {s}"))
+            }
+            None => Ok(Self::host(
+                s.parse().with_context(|| format!("invalid CIDR {s}"))?,
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+/// Parse `VpnConfig::allowed_ips`.
+pub fn parse_allowed_ips(allowed_ips: &[String]) -> Result<Vec<Cidr>> {
+    allowed_ips.iter().map(|s| s.parse()).collect()
+}
+
+/// Where a route sends matching traffic.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Target {
+    /// Straight out of an interface, as for the TUN.
+    Interface(String),
+    /// Through a gateway, as for the server host route.  The interface the
+    /// gateway is reached on is needed for IPv6 link-local gateways; it is
+    /// missing from journals written before it was recorded.
+    Gateway {
+        gateway: IpAddr,
+        interface: Option<String>,
+    },
+}
+
+/// A single routing table entry we installed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Route {
+    dest: Cidr,
+    target: Target,
+}
+
+impl fmt::Display for Route {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.target {
+            Target::Interface(name) => write!(f, "{} dev {name}", self.dest),
+            Target::Gateway {
+                gateway,
+                interface: None,
+            } => write!(f, "{} via {gateway}", self.dest),
+            Target::Gateway {
+                gateway,
+                interface: Some(name),
+            } => write!(f, "{} via {gateway} dev {name}", self.dest),
+        }
+    }
+}
+
+impl FromStr for Route {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let words: Vec<&str> = s.split_whitespace().collect();
+        let (dest, target) = match words[..] {
+            [dest, "dev", name] => (dest, Target::Interface(name.to_string())),
+            [dest, "via", gateway] => (
+                dest,
+                Target::Gateway {
+                    gateway: gateway.parse()?,
+                    interface: None,
+                },
+            ),
+            [dest, "via", gateway, "dev", name] => (
+                dest,
+                Target::Gateway {
+                    gateway: gateway.parse()?,
+                    interface: Some(name.to_string()),
+                },
+            ),
+            _ => bail!("malformed route {s:?}"),
+        };
+        Ok(Self {
+            dest: dest.parse()?,
+            target,
+        })
+    }
+}
+
+/// Routes installed for a tunnel; removed again on drop.
+pub struct Routes {
+    journal: PathBuf,
+    installed: Vec<Route>,
+}
+
+impl Routes {
+    /// Route `allowed_ips` through `interface`, keeping traffic to `server`
+    /// on its current path.  On error every route added so far is removed.
+    ///
+    /// Other tunnels' routes are left alone, so their `allowed_ips` must not
+    /// overlap with these.
+    pub fn install(interface: &str, server: IpAddr, allowed_ips: &[Cidr]) -> Result<Self> {
+        let journal = journal_path(interface);
+        recover_journal(&journal)?;
+
+        let mut routes = Self {
+            journal,
+            installed: Vec::new(),
+        };
+
+        // Look the server up before any tunnel route can shadow its path.
+        if allowed_ips.iter().any(|cidr| cidr.contains(server)) {
+            if let Some(target) = sys::current_target(server)? {
+                routes.add(Route {
+                    dest: Cidr::host(server),
+                    target,
+                })?;
+            }
+        }
+
+        for cidr in allowed_ips.iter().flat_map(|cidr| cidr.split_default()) {
+            routes.add(Route {
+                dest: cidr,
+                target: Target::Interface(interface.to_string()),
+            })?;
+        }
+
+        Ok(routes)
+    }
+
+    /// Journal and install `route`.
+    fn add(&mut self, route: Route) -> Result<()> {
+        let mut journal = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.journal)
+            .with_context(|| format!("failed to open {}", self.journal.display()))?;
+        writeln!(journal, "{route}")?;
+
+        sys::add(&route).with_context(|| format!("failed to add route {route}"))?;
+        self.installed.push(route);
+        Ok(())
+    }
+}
+
+impl Drop for Routes {
+    fn drop(&mut self) {
+        for route in self.installed.drain(..).rev() {
+            if let Err(e) = sys::delete(&route) {
+                eprintln!("failed to remove route {route}: {e}");
+            }
+        }
+        let _ = fs::remove_file(&self.journal);
+    }
+}
+
+/// Remove routes left behind by a daemon that exited without cleaning up.
+pub fn recover() -> Result<()> {
+    let entries = match fs::read_dir(JOURNAL_DIR) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e).context(format!("failed to read {JOURNAL_DIR}")),
+    };
+
+    for entry in entries {
+        let path = entry?.path();
+        let is_journal = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .is_some_and(|name| name.starts_with(JOURNAL_PREFIX) && name.ends_with(JOURNAL_SUFFIX));
+        if is_journal {
+            recover_journal(&path)?;
+        }
+    }
+    Ok(())
+}
+
+/// Remove the routes recorded in one journal, then the journal itself.
+fn recover_journal(path: &Path) -> Result<()> {
+    let journal = match fs::read_to_string(path) {
+        Ok(journal) => journal,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e).context(format!("failed to read {}", path.display())),
+    };
+
+    for line in journal.lines().rev().filter(|l| !l.trim().is_empty()) {
+        // The route may never have been added, or vanished with its interface.
+        match line.parse::<Route>() {
+            Ok(route) => {
+                if sys::delete(&route).is_ok() {
+                    println!("removed stale route {route}");
+                }
+            }
+            Err(e) => eprintln!("skipping journal entry: {e}"),
+        }
+    }
+
+    fs::remove_file(path).with_context(|| format!("failed to remove {}", path.display()))
+}
+
+fn max_prefix(addr: IpAddr) -> u8 {
+    match addr {
+        IpAddr::V4(_) => 32,
+        IpAddr::V6(_) => 128,
+    }
+}
+
+/// Clear the host bits of `addr` beyond `prefix`.
+fn mask(addr: IpAddr, prefix: u8) -> IpAddr {
+    match addr {
+        IpAddr::V4(v4) => {
+            let mask = u32::MAX.checked_shl(32 - u32::from(prefix)).unwrap_or(0);
+            IpAddr::V4(Ipv4Addr::from(u32::from(v4) & mask))
+        }
+        IpAddr::V6(v6) => {
+            let mask = u128::MAX.checked_shl(128 - u32::from(prefix)).unwrap_or(0);
+            IpAddr::V6(Ipv6Addr::from(u128::from(v6) & mask))
+        }
+    }
+}
+
+/// Run a routing command, turning a non-zero exit into an error with its stderr.
+fn run(program: &str, args: &[String]) -> Result<String> {
+    let output = Command::new(program)
+        .args(args)
+        .output()
+        .with_context(|| format!("failed to run {program}"))?;
+
+    if !output.status.success() {
+        return Err(anyhow!(
+            "{program} {} failed: {}",
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+#[cfg(target_os = "linux")]
+mod sys {
+    use super::{run, Route, Target};
+    use anyhow::Result;
+    use std::net::IpAddr;
+
+    pub fn add(route: &Route) -> Result<()> {
+        run("ip", &args("add", route)).map(drop)
+    }
+
+    pub fn delete(route: &Route) -> Result<()> {
+        run("ip", &args("del", route)).map(drop)
+    }
+
+    /// How traffic to `ip` leaves the host right now, or `None` if it is local.
+    pub fn current_target(ip: IpAddr) -> Result<Option<Target>> {
+        // e.g. "203.0.113.1 via 192.168.1.1 dev eth0 src 192.168.1.5 uid 0"
+        let output = run("ip", &["route".into(), "get".into(), ip.to_string()])?;
+        let words: Vec<&str> = output.split_whitespace().collect();
+        if words.first() == Some(&"local") {
+            return Ok(None);
+        }
+
+        let after = |key: &str| {
+            words
+                .windows(2)
+                .find(|w| w[0] == key)
+                .map(|w| w[1].to_string())
+        };
+        Ok(match (after("via"), after("dev")) {
+            (Some(gateway), interface) => Some(Target::Gateway {
+                gateway: gateway.parse()?,
+                interface,
+            }),
+            (None, Some(dev)) if dev != "lo" => Some(Target::Interface(dev)),
+            _ => None,
+        })
+    }
+
+    fn args(action: &str, route: &Route) -> Vec<String> {
+        let mut args = vec!["route".into(), action.into(), route.dest.to_string()];
+        match &route.target {
+            Target::Interface(name) => args.extend(["dev".into(), name.clone()]),
+            Target::Gateway { gateway, interface } => {
+                args.extend(["via".into(), gateway.to_string()]);
+                if let Some(name) = interface {
+                    args.extend(["dev".into(), name.clone()]);
+                }
+            }
+        }
+        args
+    }
+}
+
+#[cfg(target_os = "macos")]
+mod sys {
+    use super::{run, Route, Target};
+    use anyhow::Result;
+    use std::net::IpAddr;
+
+    pub fn add(route: &Route) -> Result<()> {
+        run("route", &args("add", route)).map(drop)
+    }
+
+    pub fn delete(route: &Route) -> Result<()> {
+        run("route", &args("delete", route)).map(drop)
+    }
+
+    /// How traffic to `ip` leaves the host right now, or `None` if it is local.
+    pub fn current_target(ip: IpAddr) -> Result<Option<Target>> {
+        // Lines such as "    gateway: 192.168.1.1" and "  interface: en0".
+        let output = run("route", &["-n".into(), "get".into(), ip.to_string()])?;
+        let field = |key: &str| {
+            output.lines().find_map(|line| {
+                let (k, v) = line.split_once(':')?;
+                (k.trim() == key).then(|| v.trim().to_string())
+            })
+        };
+
+        // On-link destinations report a gateway like "link#5" instead of an
+        // address, and link-local ones carry their scope, as in "fe80::1%en0".
+        let gateway = field("gateway").and_then(|g| {
+            let address = g.split_once('%').map_or(g.as_str(), |(address, _)| address);
+            address.parse().ok()
+        });
+        Ok(match (gateway, field("interface")) {
+            (Some(gateway), interface) => Some(Target::Gateway { gateway, interface }),
+            (None, Some(interface)) if interface != "lo0" => Some(Target::Interface(interface)),
+            _ => None,
+        })
+    }
+
+    fn args(action: &str, route: &Route) -> Vec<String> {
+        let family = if route.dest.addr().is_ipv4() {
+            "-inet"
+        } else {
+            "-inet6"
+        };
+        let mut args = vec![
+            "-q".into(),
+            "-n".into(),
+            action.into(),
+            family.into(),
+            "-net".into(),
+            route.dest.to_string(),
+        ];
+        match &route.target {
+            Target::Interface(name) => args.extend(["-interface".into(), name.clone()]),
+            Target::Gateway { gateway, interface } => {
+                args.push(gateway.to_string());
+                if let Some(name) = interface {
+                    args.extend(["-ifscope".into(), name.clone()]);
+                }
+            }
+        }
+        args
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+mod sys {
+    use super::{Route, Target};
+    use anyhow::{bail, Result};
+    use std::net::IpAddr;
+
+    pub fn add(_route: &Route) -> Result<()> {
+        bail!("routing is not supported on this platform")
+    }
+
+    pub fn delete(_route: &Route) -> Result<()> {
+        bail!("routing is not supported on this platform")
+    }
+
+    pub fn current_target(_ip: IpAddr) -> Result<Option<Target>> {
+        bail!("routing is not supported on this platform")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cidr(s: &str) -> Cidr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn cidrs_are_parsed_and_masked() {
+        assert_eq!(cidr("10.1.2.3/8").to_string(), "10.0.0.0/8");
+        assert_eq!(cidr(" 192.168.1.1 ").to_string(), "192.168.1.1/32");
+        assert_eq!(cidr("fd00:1:2::3/32").to_string(), "fd00:1::/32");
+        assert_eq!(cidr("::1").to_string(), "::1/128");
+        assert_eq!(cidr("10.0.0.1/0").to_string(), "0.0.0.0/0");
+
+        for invalid in [
+            "10.0.0.0/33",
+            "::/129",
+            "10.0.0.0/x",
+            "10.0.0/8",
+            "",
+            "example.com",
+        ] {
+            assert!(invalid.parse::<Cidr>().is_err(), "{invalid:?}");
+        }
+        assert!(parse_allowed_ips(&["10.0.0.0/8".into(), "bad".into()]).is_err());
+    }
+
+    #[test]
+    fn overlapping_networks_are_detected() {
+        let ten = cidr("10.0.0.0/8");
+        assert!(ten.overlaps(&cidr("10.1.0.0/16")));
+        assert!(cidr("10.1.0.0/16").overlaps(&ten));
+        assert!(ten.overlaps(&cidr("0.0.0.0/0")));
+        assert!(!ten.overlaps(&cidr("11.0.0.0/8")));
+        // Address families never overlap.
+        assert!(!cidr("0.0.0.0/0").overlaps(&cidr("::/0")));
+
+        assert!(ten.contains("10.255.0.1".parse().unwrap()));
+        assert!(!ten.contains("::ffff:10.0.0.1".parse().unwrap()));
+    }
+
+    #[test]
+    fn default_routes_are_split_in_halves() {
+        assert_eq!(
+            cidr("0.0.0.0/0").split_default(),
+            [cidr("0.0.0.0/1"), cidr("128.0.0.0/1")]
+        );
+        assert_eq!(
+            cidr("::/0").split_default(),
+            [cidr("::/1"), cidr("8000::/1")]
+        );
+        assert_eq!(cidr("10.0.0.0/8").split_default(), [cidr("10.0.0.0/8")]);
+    }
+
+    #[test]
+    fn routes_round_trip_through_the_journal() {
+        let routes = [
+            Route {
+                dest: cidr("0.0.0.0/1"),
+                target: Target::Interface("tun0".to_string()),
+            },
+            Route {
+                dest: cidr("203.0.113.1"),
+                target: Target::Gateway {
+                    gateway: "192.0.2.1".parse().unwrap(),
+                    interface: Some("eth0".to_string()),
+                },
+            },
+            Route {
+                dest: cidr("2001:db8::1"),
+                target: Target::Gateway {
+                    gateway: "fe80::1".parse().unwrap(),
+                    interface: Some("en0".to_string()),
+                },
+            },
+        ];
+        for route in routes {
+            let line = route.to_string();
+            assert_eq!(line.parse::<Route>().unwrap(), route, "{line}");
+        }
+    }
+
+    #[test]
+    fn journals_without_the_gateway_interface_still_parse() {
+        let line = "203.0.113.1/32 via 192.0.2.1";
+        let route: Route = line.parse().unwrap();
+        assert_eq!(
+            route,
+            Route {
+                dest: cidr("203.0.113.1"),
+                target: Target::Gateway {
+                    gateway: "192.0.2.1".parse().unwrap(),
+                    interface: None,
+                },
+            }
+        );
+        assert_eq!(route.to_string(), line);
+    }
+
+    #[test]
+    fn malformed_journal_lines_are_rejected() {
+        for line in [
+            "",
+            "10.0.0.0/8",
+            "10.0.0.0/8 dev",
+            "10.0.0.0/8 via",
+            "10.0.0.0/8 via 192.0.2.1 dev",
+            "10.0.0.0/8 via 192.0.2.1 eth0",
+            "10.0.0.0/8 via gateway",
+            "10.0.0.0/8 through tun0",
+            "10.0.0.0/33 dev tun0",
+            "10.0.0.0/8 dev tun0 extra",
+        ] {
+            assert!(line.parse::<Route>().is_err(), "{line:?}");
+        }
+    }
+}
@@ -0,0 +1,160 @@
+//! Traffic statistics kept for each tunnel.
+//!
+//! The packet loop reports what it sends, receives and drops through a
+//! [`StatsRecorder`]; [`VpnManager`](crate::vpn::VpnManager) hands out
+//! [`TunnelStats`] snapshots with the throughput averaged over
+//! [`RATE_WINDOW`].
+
+use crate::clock::Clock;
+use crate::crypto::CryptoError;
+use shared::{TunnelStats, VpnEvent};
+use std::collections::VecDeque;
+use std::sync::{Mutex, MutexGuard};
+use std::time::{Duration, Instant, SystemTime};
+use tokio::sync::broadcast;
+
+/// How far back the throughput in [`TunnelStats`] looks.
+pub const RATE_WINDOW: Duration = Duration::from_secs(5);
+
+/// Traffic within this long of a sample's start is added to that sample, so
+/// a busy tunnel keeps a few dozen samples rather than one per packet.
+const RATE_BUCKET: Duration = Duration::from_millis(250);
+
+/// Bytes moved in one direction over the last [`RATE_WINDOW`].
+#[derive(Debug, Default)]
+struct Rate {
+    /// When each sample started, and the bytes it holds; oldest first.
+    samples: VecDeque<(Instant, u64)>,
+}
+
+impl Rate {
+    fn record(&mut self, now: Instant, bytes: u64) {
+        match self.samples.back_mut() {
+            Some((start, total)) if now.duration_since(*start) < RATE_BUCKET => *total += bytes,
+            _ => self.samples.push_back((now, bytes)),
+        }
+        self.prune(now);
+    }
+
+    /// Average bytes per second over the window ending at `now`.
+    fn per_sec(&mut self, now: Instant) -> u64 {
+        self.prune(now);
+        let total: u64 = self.samples.iter().map(|(_, bytes)| bytes).sum();
+        (total as f64 / RATE_WINDOW.as_secs_f64()) as u64
+    }
+
+    fn prune(&mut self, now: Instant) {
+        while self
+            .samples
+            .front()
+            .is_some_and(|(start, _)| now.duration_since(*start) >= RATE_WINDOW)
+        {
+            self.samples.pop_front();
+        }
+    }
+}
+
+/// A tunnel's counters, shared between its packet loop and its manager.
+#[derive(Debug, Default)]
+pub(crate) struct Meter {
+    totals: TunnelStats,
+    sent: Rate,
+    received: Rate,
+}
+
+impl Meter {
+    /// The statistics as of `now`.
+    pub(crate) fn snapshot(&mut self, now: Instant) -> TunnelStats {
+        TunnelStats {
+            bytes_sent_per_sec: self.sent.per_sec(now),
+            bytes_received_per_sec: self.received.per_sec(now),
+            ..self.totals.clone()
+        }
+    }
+
+    /// Record a handshake that completed at `at` after `rtt`.
+    pub(crate) fn record_handshake(&mut self, at: SystemTime, rtt: Duration) {
+        self.totals.last_handshake = Some(at);
+        self.totals.handshake_rtt_ms = Some(millis(rtt));
+    }
+}
+
+/// `d` in whole milliseconds, as reported to clients.
+pub(crate) fn millis(d: Duration) -> u32 {
+    d.as_millis().try_into().unwrap_or(u32::MAX)
+}
+
+/// Updates a tunnel's statistics on behalf of its packet loop, and announces
+/// the handshakes it completes to the manager's subscribers.
+pub struct StatsRecorder<'a, C> {
+    /// Name of the tunnel, for its events.
+    name: &'a str,
+    meter: &'a Mutex<Meter>,
+    clock: &'a C,
+    events: &'a broadcast::Sender<VpnEvent>,
+}
+
+impl<'a, C: Clock> StatsRecorder<'a, C> {
+    pub(crate) fn new(
+        name: &'a str,
+        meter: &'a Mutex<Meter>,
+        clock: &'a C,
+        events: &'a broadcast::Sender<VpnEvent>,
+    ) -> Self {
+        Self {
+            name,
+            meter,
+            clock,
+            events,
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Meter> {
+        self.meter.lock().unwrap_or_else(|p| p.into_inner())
+    }
+
+    /// Count a datagram of `bytes` sent to the server.
+    pub fn record_sent(&self, bytes: usize) {
+        let mut meter = self.lock();
+        meter.totals.packets_sent += 1;
+        meter.totals.bytes_sent += bytes as u64;
+        meter.sent.record(Instant::now(), bytes as u64);
+    }
+
+    /// Count a datagram of `bytes` received from the server.
+    pub fn record_received(&self, bytes: usize) {
+        let mut meter = self.lock();
+        meter.totals.packets_received += 1;
+        meter.totals.bytes_received += bytes as u64;
+        meter.received.record(Instant::now(), bytes as u64);
+    }
+
+    /// Record a completed (re)handshake that took `rtt` from initiation to
+    /// response.
+    pub fn record_handshake(&self, rtt: Duration) {
+        let now = self.clock.now();
+        self.lock().record_handshake(now, rtt);
+        // Fails only when no one is subscribed.
+        let _ = self.events.send(VpnEvent::HandshakeCompleted {
+            name: self.name.to_string(),
+            rtt_ms: millis(rtt),
+        });
+    }
+
+    /// Count a packet the loop dropped because it could not be sealed or opened.
+    pub fn record_crypto_error(&self, err: &CryptoError) {
+        let mut meter = self.lock();
+        match err {
+            CryptoError::Replay(_) => meter.totals.replay_drops += 1,
+            CryptoError::AuthFailed | CryptoError::BadLength(_) => {
+                meter.totals.decrypt_failures += 1
+            }
+            CryptoError::Expired => {}
+        }
+    }
+
+    /// Count a packet the loop dropped for exceeding the tunnel MTU.
+    pub fn record_mtu_drop(&self) {
+        self.lock().totals.mtu_drops += 1;
+    }
+}
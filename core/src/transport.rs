@@ -0,0 +1,53 @@
+//! The datagram transport between the client and the server.
+//!
+//! [`VpnManager`](crate::vpn::VpnManager) reaches the server through a
+//! [`Transport`], so tests can replace the UDP socket with an in-memory one.
+
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use tokio::net::UdpSocket;
+
+/// A datagram socket connected to the server.
+pub trait Datagram: Send + Sync + 'static {
+    fn send(&self, buf: &[u8]) -> impl Future<Output = io::Result<usize>> + Send;
+    fn recv(&self, buf: &mut [u8]) -> impl Future<Output = io::Result<usize>> + Send;
+}
+
+/// Opens [`Datagram`] sockets to a server; every (re)connect gets a new one.
+pub trait Transport: Send + Sync + 'static {
+    type Socket: Datagram;
+
+    fn connect(&self, server: SocketAddr) -> impl Future<Output = io::Result<Self::Socket>> + Send;
+}
+
+/// Plain UDP, as used by the daemon.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Udp;
+
+impl Transport for Udp {
+    type Socket = UdpSocket;
+
+    /// Bind an ephemeral local port of the server's address family and
+    /// connect it to `server`.
+    async fn connect(&self, server: SocketAddr) -> io::Result<UdpSocket> {
+        let local = if server.is_ipv4() {
+            "0.0.0.0:0"
+        } else {
+            "[::]:0"
+        };
+        let sock = UdpSocket::bind(local).await?;
+        sock.connect(server).await?;
+        Ok(sock)
+    }
+}
+
+impl Datagram for UdpSocket {
+    fn send(&self, buf: &[u8]) -> impl Future<Output = io::Result<usize>> + Send {
+        UdpSocket::send(self, buf)
+    }
+
+    fn recv(&self, buf: &mut [u8]) -> impl Future<Output = io::Result<usize>> + Send {
+        UdpSocket::recv(self, buf)
+    }
+}
@@ -1,19 +1,151 @@
-use anyhow::Result;
-use tun::Configuration;
-use tun::platform::Device;
+//! TUN interface creation for the client tunnel.
 
-pub fn create_tun() -> Result<Device> {
+use anyhow::{bail, Context, Result};
+use shared::VpnConfig;
+use std::net::{IpAddr, Ipv4Addr};
+use std::process::Command;
+use tun::{AsyncDevice, Configuration, Device as _};
 
-    let mut config = Configuration::default();
+/// MTU used when the config does not set one.  Leaves room for the outer
+/// IP/UDP headers and our transport header on a 1500-byte path.
+pub const DEFAULT_MTU: u16 = 1420;
 
-    config.address("10.0.0.1");
-    config.netmask("255.255.255.0");
-    config.mtu(1500);
-    config.up();
+/// Prefix length used for IPv4 `client_ips` when the config does not set one.
+pub const DEFAULT_PREFIX_V4: u8 = 24;
 
-    let dev = Device::new(&config).unwrap();
+/// Prefix length used for IPv6 `client_ips` when the config does not set one.
+pub const DEFAULT_PREFIX_V6: u8 = 64;
 
-    println!("TUN device created");
+/// Create and bring up the TUN interface described by `config`.
+///
+/// The interface gets every address in `config.client_ips`, with
+/// `config.prefix_len` or `config.prefix_len_v6` depending on its family, MTU
+/// `config.mtu` and, if given, the name `config.interface_name`.
+pub fn create_tun(config: &VpnConfig) -> Result<AsyncDevice> {
+    if config.client_ips.is_empty() {
+        bail!("no tunnel address configured");
+    }
+
+    let prefix_v4 = config.prefix_len.unwrap_or(DEFAULT_PREFIX_V4);
+    if prefix_v4 > 32 {
+        bail!("invalid IPv4 prefix length /{prefix_v4}");
+    }
+    let prefix_v6 = config.prefix_len_v6.unwrap_or(DEFAULT_PREFIX_V6);
+    if prefix_v6 > 128 {
+        bail!("invalid IPv6 prefix length /{prefix_v6}");
+    }
+    let mtu = config.mtu.unwrap_or(DEFAULT_MTU);
+
+    let mut tun_config = Configuration::default();
+    tun_config.mtu(i32::from(mtu)).up();
+    if let Some(name) = &config.interface_name {
+        tun_config.name(name);
+    }
+
+    // The tun crate can only assign a single IPv4 address; the rest are added
+    // once the interface exists.
+    let first_v4 = config.client_ips.iter().find_map(|ip| match ip {
+        IpAddr::V4(v4) => Some(*v4),
+        IpAddr::V6(_) => None,
+    });
+    if let Some(address) = first_v4 {
+        tun_config.address(address).netmask(netmask_v4(prefix_v4));
+    }
+
+    let dev = tun::create_as_async(&tun_config).context("failed to create TUN device")?;
+    let name = dev.get_ref().name()?;
+
+    for &address in &config.client_ips {
+        let prefix = match address {
+            IpAddr::V4(v4) if Some(v4) == first_v4 => continue,
+            IpAddr::V4(_) => prefix_v4,
+            IpAddr::V6(_) => prefix_v6,
+        };
+        sys::add_address(&name, address, prefix)
+            .with_context(|| format!("failed to add {address}/{prefix} to {name}"))?;
+    }
+
+    println!("TUN device created: {name}, MTU {mtu}");
 
     Ok(dev)
-}
\ No newline at end of file
+}
+
+/// The IPv4 netmask for a prefix length of at most 32.
+fn netmask_v4(prefix: u8) -> Ipv4Addr {
+    Ipv4Addr::from(u32::MAX.checked_shl(32 - u32::from(prefix)).unwrap_or(0))
+}
+
+/// Run an interface configuration command, failing with its stderr.
+fn run(program: &str, args: &[String]) -> Result<()> {
+    let output = Command::new(program)
+        .args(args)
+        .output()
+        .with_context(|| format!("failed to run {program}"))?;
+
+    if !output.status.success() {
+        bail!(
+            "{program} {} failed: {}",
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+mod sys {
+    use super::run;
+    use anyhow::Result;
+    use std::net::IpAddr;
+
+    pub fn add_address(interface: &str, address: IpAddr, prefix: u8) -> Result<()> {
+        run(
+            "ip",
+            &[
+                "addr".into(),
+                "add".into(),
+                format!("{address}/{prefix}"),
+                "dev".into(),
+                interface.into(),
+            ],
+        )
+    }
+}
+
+#[cfg(target_os = "macos")]
+mod sys {
+    use super::run;
+    use anyhow::Result;
+    use std::net::IpAddr;
+
+    pub fn add_address(interface: &str, address: IpAddr, prefix: u8) -> Result<()> {
+        let args = match address {
+            IpAddr::V4(_) => vec![
+                interface.into(),
+                "inet".into(),
+                format!("{address}/{prefix}"),
+                address.to_string(),
+                "alias".into(),
+            ],
+            IpAddr::V6(_) => vec![
+                interface.into(),
+                "inet6".into(),
+                address.to_string(),
+                "prefixlen".into(),
+                prefix.to_string(),
+                "alias".into(),
+            ],
+        };
+        run("ifconfig", &args)
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+mod sys {
+    use anyhow::{bail, Result};
+    use std::net::IpAddr;
+
+    pub fn add_address(_interface: &str, _address: IpAddr, _prefix: u8) -> Result<()> {
+        bail!("additional tunnel addresses are not supported on this platform")
+    }
+}
@@ -1,13 +1,310 @@
 //! WireGuard-style packet tunnel: reads from the TUN interface, encrypts,
-//! and forwards over UDP to the VPN server, and vice-versa.
+//! and forwards over a [`Datagram`] socket (UDP outside tests) to the VPN
+//! server, and vice-versa.
 
+use crate::clock::Clock;
+use crate::crypto::{CryptoError, Keyring, Session};
+use crate::handshake::{self, Initiator, TransportKeys};
+use crate::keys::{ConfigKeys, PresharedKey};
+use crate::platform::TunDevice;
+use crate::stats::StatsRecorder;
+use crate::transport::Datagram;
+use crate::wire::Message;
 use anyhow::Result;
-use std::net::SocketAddr;
-use tokio::net::UdpSocket;
-
-/// Open a UDP socket bound to an ephemeral local port and connected to `addr`.
-pub async fn connect_udp(addr: SocketAddr) -> Result<UdpSocket> {
-    let sock = UdpSocket::bind("0.0.0.0:0").await?;
-    sock.connect(addr).await?;
-    Ok(sock)
+use futures::{SinkExt, StreamExt};
+use shared::VpnConfig;
+use std::time::Duration;
+use tokio::time::{timeout, Instant};
+use tokio_util::sync::CancellationToken;
+use tun::TunPacket;
+
+/// How long to wait for a handshake response before retrying.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Number of handshake initiations sent before giving up.
+const HANDSHAKE_ATTEMPTS: u32 = 3;
+
+/// How often the packet loop checks session lifetimes.
+const TIMER_TICK: Duration = Duration::from_secs(1);
+
+/// How long sent traffic may go unanswered before the session is suspected
+/// dead and a handshake is forced, as WireGuard's keepalive plus rekey
+/// timeouts.
+pub const NO_REPLY_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// Run the Noise IK handshake against the server over a connected socket.
+///
+/// Authenticates with `config.client_private_key`, verifies the server holds
+/// `config.server_public_key`, and returns fresh transport keys for the session
+/// along with the round trip time of the attempt that got through.
+pub async fn handshake(
+    sock: &impl Datagram,
+    config: &VpnConfig,
+) -> Result<(TransportKeys, Duration)> {
+    let keys = ConfigKeys::from_config(config)?;
+
+    let mut buf = [0u8; 2048];
+
+    for _ in 0..HANDSHAKE_ATTEMPTS {
+        let (initiator, init) = initiation(&keys)?;
+        sock.send(&init).await?;
+        let sent = Instant::now();
+
+        let Ok(received) = timeout(HANDSHAKE_TIMEOUT, sock.recv(&mut buf)).await else {
+            continue;
+        };
+        let len = received?;
+
+        if let Some(Message::HandshakeResponse {
+            sender,
+            receiver,
+            payload,
+        }) = Message::parse(&buf[..len])
+        {
+            if receiver == initiator.local_index() {
+                return Ok((initiator.finish(sender, payload)?, sent.elapsed()));
+            }
+        }
+    }
+
+    Err(anyhow::anyhow!(
+        "handshake with {} timed out",
+        config.server_addr
+    ))
+}
+
+/// Start a handshake and return the initiator with its framed first message.
+fn initiation(keys: &ConfigKeys) -> Result<(Initiator, Vec<u8>)> {
+    let preshared_key = keys
+        .preshared_key
+        .as_ref()
+        .map_or(&handshake::NO_PRESHARED_KEY, PresharedKey::as_bytes);
+    let (initiator, payload) = Initiator::new(
+        keys.private_key.as_bytes(),
+        keys.server_key.as_bytes(),
+        preshared_key,
+    )?;
+    let datagram = Message::HandshakeInit {
+        sender: initiator.local_index(),
+        payload: &payload,
+    }
+    .encode();
+    Ok((initiator, datagram))
+}
+
+/// Why [`run`] returned.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Exit {
+    /// `shutdown` fired.
+    Shutdown,
+    /// The session is dead: the server stopped answering handshakes or the
+    /// socket failed.  The TUN device is still usable, so a new handshake may
+    /// bring the tunnel back.
+    SessionLost(String),
+}
+
+/// Forward packets between the TUN device and the server until `shutdown` fires
+/// or the session dies.
+///
+/// Outbound IP packets read from `tun` are encrypted with the session's
+/// send key and framed as transport messages; inbound transport messages
+/// addressed to our session index are decrypted and written back to `tun`.
+/// Outbound messages are numbered with a monotonic counter that also forms the
+/// nonce; inbound counters go through a replay window.  Packets that fail to
+/// authenticate or are replayed are counted via
+/// [`StatsRecorder::record_crypto_error`] and dropped without stopping the
+/// loop, as are packets larger than the tunnel MTU, via
+/// [`StatsRecorder::record_mtu_drop`].  Datagrams sent and received are
+/// counted through [`StatsRecorder::record_sent`] and
+/// [`StatsRecorder::record_received`].
+///
+/// The loop rekeys on its own: once the session reaches `REKEY_AFTER_TIME` or
+/// `REKEY_AFTER_MESSAGES` (see [`crate::crypto`]) a new handshake is sent, and
+/// the old keys keep decrypting for `KEEP_PREVIOUS_FOR` after the switch.
+/// Completed rekeys are reported, with their round trip time, through
+/// [`StatsRecorder::record_handshake`].
+///
+/// With `config.persistent_keepalive_secs` set, an empty authenticated
+/// message goes out whenever nothing else has been sent for that long.
+///
+/// A session is presumed dead once sent traffic has gone unanswered for
+/// [`NO_REPLY_TIMEOUT`], which forces a handshake, or once
+/// `HANDSHAKE_ATTEMPTS` handshakes in a row get no response; the loop then
+/// returns [`Exit::SessionLost`].  Errors are reserved for a failing TUN
+/// device.
+pub async fn run(
+    tun: &mut impl TunDevice,
+    socket: impl Datagram,
+    config: &VpnConfig,
+    keys: TransportKeys,
+    stats: &StatsRecorder<'_, impl Clock>,
+    shutdown: CancellationToken,
+) -> Result<Exit> {
+    let config_keys = ConfigKeys::from_config(config)?;
+    let mtu = usize::from(config.mtu.unwrap_or(crate::tun::DEFAULT_MTU));
+
+    let mut buf = [0u8; 2048];
+
+    let mut keyring = Keyring::default();
+    let now = Instant::now().into_std();
+    keyring.install(Session::new(keys, now), now);
+
+    // Rekey handshake in flight, and when it was sent.
+    let mut rekey: Option<(Initiator, Instant)> = None;
+    // Rekey initiations sent since the last response.
+    let mut unanswered = 0;
+    // When we first sent data that the server has not answered yet.
+    let mut awaiting_reply: Option<Instant> = None;
+    let keepalive = config
+        .persistent_keepalive_secs
+        .filter(|&secs| secs > 0)
+        .map(|secs| Duration::from_secs(secs.into()));
+    let mut last_sent = Instant::now();
+    let mut timer = tokio::time::interval(TIMER_TICK);
+
+    // An empty message confirms the new keys so the server starts using them.
+    if let Err(e) = send_keepalive(&socket, &mut keyring).await {
+        return Ok(Exit::SessionLost(format!("send failed: {e}")));
+    }
+
+    loop {
+        tokio::select! {
+            _ = shutdown.cancelled() => break,
+
+            _ = timer.tick() => {
+                let now = Instant::now().into_std();
+                keyring.expire(now);
+
+                let silent = awaiting_reply.is_some_and(|since| since.elapsed() >= NO_REPLY_TIMEOUT);
+                let due = silent || keyring.current_mut(now).is_none_or(|s| s.needs_rekey(now));
+                let waiting = rekey
+                    .as_ref()
+                    .is_some_and(|(_, sent)| sent.elapsed() < HANDSHAKE_TIMEOUT);
+
+                if due && !waiting {
+                    if rekey.take().is_some() {
+                        unanswered += 1;
+                    }
+                    if unanswered >= HANDSHAKE_ATTEMPTS {
+                        return Ok(Exit::SessionLost(format!(
+                            "no handshake response from {}",
+                            config.server_addr
+                        )));
+                    }
+
+                    let (initiator, init) = initiation(&config_keys)?;
+                    if let Err(e) = socket.send(&init).await {
+                        return Ok(Exit::SessionLost(format!("send failed: {e}")));
+                    }
+                    rekey = Some((initiator, Instant::now()));
+                }
+
+                if keepalive.is_some_and(|interval| last_sent.elapsed() >= interval) {
+                    if let Err(e) = send_keepalive(&socket, &mut keyring).await {
+                        return Ok(Exit::SessionLost(format!("send failed: {e}")));
+                    }
+                    last_sent = Instant::now();
+                }
+            }
+
+            packet = tun.next() => {
+                let Some(packet) = packet else { break };
+                let packet = packet?;
+                if packet.get_bytes().len() > mtu {
+                    stats.record_mtu_drop();
+                    continue;
+                }
+
+                let now = Instant::now().into_std();
+                let sealed = keyring
+                    .current_mut(now)
+                    .ok_or(CryptoError::Expired)
+                    .and_then(|s| s.seal(packet.get_bytes(), now));
+                let datagram = match sealed {
+                    Ok(datagram) => datagram,
+                    Err(e) => {
+                        stats.record_crypto_error(&e);
+                        continue;
+                    }
+                };
+
+                if let Err(e) = socket.send(&datagram).await {
+                    return Ok(Exit::SessionLost(format!("send failed: {e}")));
+                }
+                last_sent = Instant::now();
+                awaiting_reply.get_or_insert_with(Instant::now);
+                stats.record_sent(datagram.len());
+            }
+
+            received = socket.recv(&mut buf) => {
+                let len = match received {
+                    Ok(len) => len,
+                    Err(e) => return Ok(Exit::SessionLost(format!("receive failed: {e}"))),
+                };
+
+                match Message::parse(&buf[..len]) {
+                    Some(Message::HandshakeResponse { sender, receiver, payload }) => {
+                        let Some((initiator, sent)) =
+                            rekey.take_if(|(i, _)| i.local_index() == receiver)
+                        else {
+                            continue;
+                        };
+
+                        match initiator.finish(sender, payload) {
+                            Ok(keys) => {
+                                unanswered = 0;
+                                awaiting_reply = None;
+                                let now = Instant::now().into_std();
+                                keyring.install(Session::new(keys, now), now);
+                                stats.record_handshake(sent.elapsed());
+                                if let Err(e) = send_keepalive(&socket, &mut keyring).await {
+                                    return Ok(Exit::SessionLost(format!("send failed: {e}")));
+                                }
+                                last_sent = Instant::now();
+                            }
+                            Err(e) => eprintln!("rekey handshake failed: {e}"),
+                        }
+                    }
+
+                    Some(Message::Transport { receiver, counter, ciphertext }) => {
+                        let Some(opened) = keyring.open(receiver, counter, ciphertext, Instant::now().into_std()) else {
+                            continue;
+                        };
+
+                        let plaintext = match opened {
+                            Ok(plaintext) => plaintext,
+                            Err(e) => {
+                                stats.record_crypto_error(&e);
+                                continue;
+                            }
+                        };
+
+                        awaiting_reply = None;
+                        // The TUN device would reject it, failing the loop.
+                        if plaintext.len() > mtu {
+                            stats.record_mtu_drop();
+                            continue;
+                        }
+                        if !plaintext.is_empty() {
+                            tun.send(TunPacket::new(plaintext)).await?;
+                        }
+                        stats.record_received(len);
+                    }
+
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    Ok(Exit::Shutdown)
+}
+
+/// Send an authenticated empty transport message under the current session.
+async fn send_keepalive(socket: &impl Datagram, keyring: &mut Keyring) -> Result<()> {
+    let now = Instant::now().into_std();
+    if let Some(datagram) = keyring.current_mut(now).and_then(|s| s.seal(&[], now).ok()) {
+        socket.send(&datagram).await?;
+    }
+    Ok(())
 }
@@ -1,112 +1,1235 @@
 //! High-level VPN tunnel management.
 //!
-//! These functions are used by the daemon to manage the VPN connection lifecycle.
+//! [`VpnManager`] is used by the daemon to manage the VPN connection lifecycle.
 //! The actual WireGuard handshake requires root access (TUN interface creation),
-//! so these functions are typically called from the privileged daemon process.
-
-use anyhow::Result;
-use shared::{TunnelStats, TunnelStatus, VpnConfig};
-use std::sync::{Arc, Mutex};
-use std::time::SystemTime;
-
-/// Opaque handle returned by [`connect`]. Pass to [`disconnect`] to tear down
-/// the tunnel, and to [`get_stats`] to read transfer counters.
-pub struct TunnelHandle {
-    pub(crate) config: VpnConfig,
-    pub(crate) connected_at: SystemTime,
-    pub(crate) stats: Arc<Mutex<TunnelStats>>,
+//! so the manager is typically driven from the privileged daemon process.
+//!
+//! Several tunnels can be up at once, each under its own name, as long as
+//! their `allowed_ips` do not overlap and at most one of them sets DNS servers.
+
+use crate::clock::{Clock, SystemClock};
+use crate::killswitch::Allowed;
+use crate::platform::{Native, Platform};
+use crate::route::Cidr;
+use crate::stats::{millis, Meter};
+use crate::transport::{Transport, Udp};
+use anyhow::{bail, Result};
+use shared::{TunnelInfo, TunnelStats, TunnelStatus, VpnConfig, VpnEvent};
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant, SystemTime};
+use tokio::sync::broadcast;
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
+
+/// Where a tunnel is in its lifecycle.
+///
+/// [`VpnManager::connect`] starts in `Connecting` and moves to `Connected`
+/// once the handshake succeeds and the interface is up.  When the packet loop
+/// loses the session, [`VpnManager::supervise`] moves it to `Reconnecting`
+/// until a new handshake succeeds, and to `Failed` if the TUN device fails.
+#[derive(Debug, Clone, PartialEq)]
+enum State {
+    Connecting,
+    Connected {
+        since: SystemTime,
+    },
+    Reconnecting {
+        attempt: u32,
+        /// `None` while the attempt is in progress.
+        next_attempt: Option<SystemTime>,
+    },
+    /// Stopped on its own; reported until the next connect or disconnect.
+    Failed(String),
+}
+
+/// Everything one tunnel set up, owned by its [`VpnManager`].
+struct TunnelHandle<P: Platform> {
+    /// Distinguishes this connection from earlier ones, so a connect still in
+    /// progress notices when a disconnect has taken its handle.
+    id: u64,
+    name: String,
+    config: VpnConfig,
+    /// `config.allowed_ips`, parsed.
+    allowed_ips: Vec<Cidr>,
+    state: State,
+    meter: Arc<Mutex<Meter>>,
+    /// Cancelled on disconnect to stop the handshake or packet loop.
+    shutdown: CancellationToken,
+    /// The running packet loop, if a TUN device was created.
+    task: Option<JoinHandle<()>>,
+    /// Routes through the TUN device; removed when the handle is dropped.
+    routes: Option<P::Routes>,
+    /// Resolver settings; restored when the handle is dropped.
+    dns: Option<P::Dns>,
+    /// Name of the TUN interface, if one was created.
+    interface: Option<String>,
 }
 
-/// Global tunnel state shared across the process.
-static TUNNEL: Mutex<Option<TunnelHandle>> = Mutex::new(None);
+impl<P: Platform> TunnelHandle<P> {
+    fn new(id: u64, name: String, config: VpnConfig, allowed_ips: Vec<Cidr>) -> Self {
+        Self {
+            id,
+            name,
+            config,
+            allowed_ips,
+            state: State::Connecting,
+            meter: Arc::default(),
+            shutdown: CancellationToken::new(),
+            task: None,
+            routes: None,
+            dns: None,
+            interface: None,
+        }
+    }
+
+    /// Whether the tunnel is up or on its way, as opposed to having failed
+    /// before its interface existed.  Only live tunnels are let through the
+    /// kill switch and can conflict with a new one.
+    fn is_live(&self) -> bool {
+        !matches!(self.state, State::Failed(_)) || self.interface.is_some()
+    }
+
+    fn status(&self) -> TunnelStatus {
+        let server = self.config.server_addr.to_string();
+
+        match &self.state {
+            State::Connecting => TunnelStatus::Connecting,
+            State::Connected { since } => TunnelStatus::Connected {
+                since: *since,
+                server,
+            },
+            State::Reconnecting {
+                attempt,
+                next_attempt,
+            } => TunnelStatus::Reconnecting {
+                server,
+                attempt: *attempt,
+                next_attempt: *next_attempt,
+            },
+            State::Failed(error) => TunnelStatus::Error(error.clone()),
+        }
+    }
+
+    fn stats(&self) -> TunnelStats {
+        self.meter
+            .lock()
+            .unwrap_or_else(|p| p.into_inner())
+            .snapshot(Instant::now())
+    }
 
-/// Acquire the global tunnel lock, recovering from a poisoned mutex.
-fn lock_tunnel() -> std::sync::MutexGuard<'static, Option<TunnelHandle>> {
-    TUNNEL.lock().unwrap_or_else(|p| p.into_inner())
+    /// Undo everything the tunnel set up.
+    ///
+    /// Stops the packet loop and waits for it to release the TUN device.  The
+    /// kill switch is shared between tunnels, so the caller updates it after.
+    async fn teardown(self) {
+        // Restore DNS and the routing table while the interface still exists,
+        // off the runtime as both run system tools.
+        let (dns, routes) = (self.dns, self.routes);
+        let _ = tokio::task::spawn_blocking(move || {
+            drop(dns);
+            drop(routes);
+        })
+        .await;
+
+        self.shutdown.cancel();
+        if let Some(task) = self.task {
+            let _ = task.await;
+        }
+    }
 }
 
-/// Establish a VPN tunnel with the given configuration.
-///
-/// On macOS this creates a TUN interface and performs the WireGuard handshake.
-/// On other platforms (e.g. during CI) the connection is simulated so that the
-/// build succeeds and unit tests can exercise the state machine.
-pub fn connect(config: VpnConfig) -> Result<()> {
-    let mut guard = lock_tunnel();
-
-    if guard.is_some() {
-        return Err(anyhow::anyhow!("already connected – disconnect first"));
-    }
-
-    // On macOS the real TUN device would be created here.  We keep the actual
-    // interface creation in tun::create_tun() so it can be called with the
-    // necessary privileges.
-    #[cfg(target_os = "macos")]
-    crate::tun::create_tun()?;
-
-    let stats = Arc::new(Mutex::new(TunnelStats {
-        bytes_sent: 0,
-        bytes_received: 0,
-        last_handshake: Some(SystemTime::now()),
-    }));
-
-    *guard = Some(TunnelHandle {
-        config,
-        connected_at: SystemTime::now(),
-        stats,
-    });
-
-    Ok(())
+/// The tunnels of a [`VpnManager`], by name.
+struct Tunnels<P: Platform> {
+    handles: BTreeMap<String, TunnelHandle<P>>,
 }
 
-/// Tear down the active VPN tunnel.
-pub fn disconnect() -> Result<()> {
-    let mut guard = lock_tunnel();
+impl<P: Platform> Default for Tunnels<P> {
+    fn default() -> Self {
+        Self {
+            handles: BTreeMap::new(),
+        }
+    }
+}
+
+impl<P: Platform> Tunnels<P> {
+    fn get_mut(&mut self, id: u64) -> Option<&mut TunnelHandle<P>> {
+        self.handles.values_mut().find(|h| h.id == id)
+    }
+
+    /// Refuse to bring up `name` with `config` next to the other live tunnels
+    /// if they would fight over the routing table or the resolver.
+    fn check_conflicts(&self, name: &str, config: &VpnConfig, allowed_ips: &[Cidr]) -> Result<()> {
+        let others = self
+            .handles
+            .iter()
+            .filter(|(other, h)| other.as_str() != name && h.is_live());
 
-    if guard.is_none() {
-        return Err(anyhow::anyhow!("not connected"));
+        for (other, h) in others {
+            if !config.dns_servers.is_empty() && !h.config.dns_servers.is_empty() {
+                bail!("tunnel {other:?} already sets the DNS servers");
+            }
+            for ours in allowed_ips {
+                if let Some(theirs) = h.allowed_ips.iter().find(|theirs| ours.overlaps(theirs)) {
+                    bail!("allowed IPs {ours} overlap with {theirs} of tunnel {other:?}");
+                }
+            }
+        }
+        Ok(())
     }
 
-    *guard = None;
-    Ok(())
+    /// What the kill switch must let through for the live tunnels: their
+    /// servers and interfaces.  `None` if there are none.
+    fn allowed(&self) -> Option<Allowed> {
+        let live: Vec<&TunnelHandle<P>> = self.handles.values().filter(|h| h.is_live()).collect();
+        if live.is_empty() {
+            return None;
+        }
+        Some(Allowed {
+            servers: live.iter().map(|h| h.config.server_addr).collect(),
+            interfaces: live.iter().filter_map(|h| h.interface.clone()).collect(),
+        })
+    }
 }
 
-/// Return the current tunnel status without blocking.
-pub fn get_status() -> TunnelStatus {
-    let guard = lock_tunnel();
+/// The kill switch of a [`VpnManager`].
+struct Firewall<P: Platform> {
+    /// Firewall block on non-tunnel traffic, shared by all live tunnels.
+    /// Outlives their packet loops, so traffic stays blocked if a tunnel dies
+    /// until it is disconnected.
+    kill_switch: Option<P::KillSwitch>,
+    /// Whether `kill_switch` was kept from a daemon that stopped with tunnels
+    /// up.  It then blocks everything even with no tunnel live, until a
+    /// disconnect or the kill switch is turned off.
+    stranded: bool,
+}
 
-    match &*guard {
-        None => TunnelStatus::Disconnected,
-        Some(h) => TunnelStatus::Connected {
-            since: h.connected_at,
-            server: h.config.server_addr.to_string(),
-        },
+impl<P: Platform> Default for Firewall<P> {
+    fn default() -> Self {
+        Self {
+            kill_switch: None,
+            stranded: false,
+        }
     }
 }
 
-/// Return transfer statistics for the active tunnel.
+/// Owns the VPN tunnels and everything they changed on the system.
+///
+/// Cheap to clone; clones share the same tunnels.  The daemon builds one at
+/// startup with [`VpnManager::new`].  Tests can build as many as they like with
+/// [`VpnManager::with_platform`], reaching their server through their own
+/// [`Transport`], reading time from their own [`Clock`] and making their
+/// system changes through their own [`Platform`].  Routes, DNS and kill
+/// switch rules are system-wide, though, so only one manager per machine
+/// should use the [`Native`] platform.
 ///
-/// Returns zeroed stats when not connected.
-pub fn get_stats() -> TunnelStats {
-    let guard = lock_tunnel();
-
-    match &*guard {
-        None => TunnelStats {
-            bytes_sent: 0,
-            bytes_received: 0,
-            last_handshake: None,
-        },
-        Some(h) => h.stats.lock().unwrap_or_else(|p| p.into_inner()).clone(),
+/// Status changes, handshakes and errors are announced as [`VpnEvent`]s to
+/// every receiver from [`subscribe`](Self::subscribe).
+pub struct VpnManager<T = Udp, C = SystemClock, P: Platform = Native> {
+    inner: Arc<Inner<T, C, P>>,
+}
+
+struct Inner<T, C, P: Platform> {
+    transport: T,
+    clock: C,
+    platform: P,
+    tunnels: Mutex<Tunnels<P>>,
+    /// Held while the kill switch rules change, which takes a while, so
+    /// changes apply in order without holding up `tunnels`.
+    firewall: tokio::sync::Mutex<Firewall<P>>,
+    /// Source of [`TunnelHandle::id`].
+    next_id: AtomicU64,
+    /// Whether connections are protected by a [`KillSwitch`].
+    kill_switch: AtomicBool,
+    events: broadcast::Sender<VpnEvent>,
+}
+
+/// Events kept for each subscriber that falls behind; older ones are lost.
+const EVENT_BUFFER: usize = 64;
+
+impl<T, C, P: Platform> Clone for VpnManager<T, C, P> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: Arc::clone(&self.inner),
+        }
+    }
+}
+
+impl VpnManager {
+    /// A manager that reaches servers over UDP and uses the system clock,
+    /// with the kill switch on if it was left on.
+    pub fn new() -> Self {
+        Self::with_platform(Udp, SystemClock, Native)
     }
 }
 
-/// Update the byte counters for the active tunnel (called by the packet loop).
-pub fn update_stats(bytes_sent: u64, bytes_received: u64) {
-    let guard = lock_tunnel();
+impl Default for VpnManager {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T: Transport, C: Clock, P: Platform> VpnManager<T, C, P> {
+    /// A manager with the kill switch on if `platform` saved it so.
+    pub fn with_platform(transport: T, clock: C, platform: P) -> Self {
+        let kill_switch = platform.saved_kill_switch();
+        Self {
+            inner: Arc::new(Inner {
+                transport,
+                clock,
+                platform,
+                tunnels: Mutex::default(),
+                firewall: tokio::sync::Mutex::default(),
+                next_id: AtomicU64::new(0),
+                kill_switch: AtomicBool::new(kill_switch),
+                events: broadcast::channel(EVENT_BUFFER).0,
+            }),
+        }
+    }
+
+    /// Receive every [`VpnEvent`] from now on.
+    ///
+    /// A receiver that falls more than a few dozen events behind loses the
+    /// oldest ones and gets [`broadcast::error::RecvError::Lagged`].
+    pub fn subscribe(&self) -> broadcast::Receiver<VpnEvent> {
+        self.inner.events.subscribe()
+    }
+
+    /// Tell subscribers about `event`.
+    fn announce(&self, event: VpnEvent) {
+        // Fails only when no one is subscribed.
+        let _ = self.inner.events.send(event);
+    }
+
+    /// Move `h` to `state` and announce its new status.
+    fn transition(&self, h: &mut TunnelHandle<P>, state: State) {
+        h.state = state;
+        self.announce(VpnEvent::StatusChanged {
+            name: h.name.clone(),
+            status: h.status(),
+        });
+    }
+
+    /// Move `h` to [`State::Connected`] after a handshake that took `rtt`.
+    fn mark_connected(&self, h: &mut TunnelHandle<P>, rtt: Duration) {
+        let now = self.inner.clock.now();
+        h.meter
+            .lock()
+            .unwrap_or_else(|p| p.into_inner())
+            .record_handshake(now, rtt);
+        self.transition(h, State::Connected { since: now });
+        self.announce(VpnEvent::HandshakeCompleted {
+            name: h.name.clone(),
+            rtt_ms: millis(rtt),
+        });
+    }
+
+    /// Acquire the tunnels lock, recovering from a poisoned mutex.
+    fn lock(&self) -> MutexGuard<'_, Tunnels<P>> {
+        self.inner.tunnels.lock().unwrap_or_else(|p| p.into_inner())
+    }
+
+    /// Run `f` on the tunnel with handle `id`, failing if it has since been
+    /// disconnected.
+    fn with_tunnel<R>(
+        &self,
+        id: u64,
+        f: impl FnOnce(&mut TunnelHandle<P>) -> Result<R>,
+    ) -> Result<R> {
+        match self.lock().get_mut(id) {
+            Some(handle) => f(handle),
+            None => bail!("connection cancelled"),
+        }
+    }
+
+    /// Run `f` with the platform on a thread that may block, as its changes
+    /// run system tools and would otherwise stall the runtime.
+    async fn blocking<R: Send + 'static>(&self, f: impl FnOnce(&P) -> R + Send + 'static) -> R {
+        let inner = Arc::clone(&self.inner);
+        tokio::task::spawn_blocking(move || f(&inner.platform))
+            .await
+            .unwrap_or_else(|e| std::panic::resume_unwind(e.into_panic()))
+    }
+
+    /// Bring the kill switch in line with the tunnels after one changed: on
+    /// while enabled and any of them is live, letting through their servers
+    /// and interfaces.
+    ///
+    /// The tunnels lock is only held to read them, so status queries carry on
+    /// while the rules change.
+    async fn update_kill_switch(&self) -> Result<()> {
+        let mut firewall = self.inner.firewall.lock().await;
+        let enabled = self.inner.kill_switch.load(Ordering::SeqCst);
+        let allowed = match self.lock().allowed() {
+            Some(allowed) => Some(allowed),
+            // A block kept from a crash lets nothing through.
+            None if firewall.stranded => Some(Allowed::default()),
+            None => None,
+        };
+
+        let kill_switch = firewall.kill_switch.take();
+        let Some(allowed) = allowed.filter(|_| enabled) else {
+            firewall.stranded = false;
+            if let Some(kill_switch) = kill_switch {
+                self.blocking(move |_| drop(kill_switch)).await;
+            }
+            return Ok(());
+        };
+        match kill_switch {
+            Some(mut kill_switch) => {
+                let (kill_switch, result) = self
+                    .blocking(move |platform| {
+                        let result = platform.update_kill_switch(&mut kill_switch, allowed);
+                        (kill_switch, result)
+                    })
+                    .await;
+                firewall.kill_switch = Some(kill_switch);
+                result
+            }
+            None => {
+                let kill_switch = self
+                    .blocking(move |platform| platform.enable_kill_switch(allowed))
+                    .await?;
+                firewall.kill_switch = Some(kill_switch);
+                Ok(())
+            }
+        }
+    }
+
+    /// Establish a VPN tunnel called `name` with the given configuration.
+    ///
+    /// Fails if a tunnel of that name is already up, or if the new one would
+    /// route the same networks as, or also set DNS servers next to, another.
+    /// The tunnel is reported as [`TunnelStatus::Connecting`] while the Noise
+    /// handshake with the server runs, so a wrong key or an unreachable server is
+    /// reported here rather than after the tunnel is up; the error is also kept as
+    /// [`TunnelStatus::Error`] until the next connect or disconnect.
+    /// It then creates a TUN interface, routes `config.allowed_ips` through it,
+    /// points the system resolver at `config.dns_servers` and spawns the packet
+    /// loop from [`crate::tunnel::run`]; all of this is undone by
+    /// [`disconnect`](Self::disconnect). With the kill switch on, other traffic
+    /// is blocked from before the handshake on.
+    pub async fn connect(&self, name: &str, config: VpnConfig) -> Result<()> {
+        if name.is_empty() {
+            bail!("tunnel name must not be empty");
+        }
+        // Reject malformed keys and networks before touching the network.
+        crate::keys::ConfigKeys::from_config(&config)?;
+        let allowed_ips = crate::route::parse_allowed_ips(&config.allowed_ips)?;
+
+        let id = self.inner.next_id.fetch_add(1, Ordering::Relaxed);
+        let handle = TunnelHandle::new(id, name.to_string(), config.clone(), allowed_ips.clone());
+        let shutdown = handle.shutdown.clone();
+
+        let failed = {
+            let mut tunnels = self.lock();
+            if tunnels
+                .handles
+                .get(name)
+                .is_some_and(|h| !matches!(h.state, State::Failed(_)))
+            {
+                bail!("tunnel {name:?} is already connected – disconnect it first");
+            }
+            tunnels.check_conflicts(name, &config, &allowed_ips)?;
+            self.announce(VpnEvent::StatusChanged {
+                name: name.to_string(),
+                status: TunnelStatus::Connecting,
+            });
+            tunnels.handles.insert(name.to_string(), handle)
+        };
+        // A tunnel that failed on its own is replaced rather than refused.
+        if let Some(failed) = failed {
+            failed.teardown().await;
+        }
+
+        if let Err(e) = self.establish(id, &config, &allowed_ips, &shutdown).await {
+            let _ = self.with_tunnel(id, |h| {
+                self.transition(h, State::Failed(format!("{e:#}")));
+                Ok(())
+            });
+            // Nothing was protected yet, so stop letting the server through.
+            if let Err(e) = self.update_kill_switch().await {
+                eprintln!("failed to update kill switch: {e}");
+            }
+            return Err(e);
+        }
+
+        Ok(())
+    }
+
+    /// The steps of [`connect`](Self::connect) after the handle is in place.
+    /// Routes and DNS are handed to the handle as soon as they are set up, so
+    /// a disconnect that gets in first undoes them; on failure they are undone
+    /// here.
+    async fn establish(
+        &self,
+        id: u64,
+        config: &VpnConfig,
+        allowed_ips: &[Cidr],
+        shutdown: &CancellationToken,
+    ) -> Result<()> {
+        // Block other traffic, if asked to, from before the handshake on.
+        self.update_kill_switch().await?;
+
+        let socket = self.inner.transport.connect(config.server_addr).await?;
+        let (keys, rtt) = tokio::select! {
+            result = crate::tunnel::handshake(&socket, config) => result?,
+            _ = shutdown.cancelled() => bail!("connection cancelled"),
+        };
+
+        let (mut tun, interface, routes, dns) = {
+            let config = config.clone();
+            let allowed_ips = allowed_ips.to_vec();
+            self.blocking(move |platform| {
+                let (tun, interface) = platform.create_tun(&config)?;
+                let routes =
+                    platform.install_routes(&interface, config.server_addr.ip(), &allowed_ips)?;
+                // Without servers of its own the tunnel leaves the resolver,
+                // and its journal, to the tunnel that has some.
+                let dns = match config.dns_servers.as_slice() {
+                    [] => None,
+                    servers => Some(platform.apply_dns(&interface, servers)?),
+                };
+                anyhow::Ok((tun, interface, routes, dns))
+            })
+            .await?
+        };
+
+        let mut setup = Some((routes, dns));
+        let registered = self.with_tunnel(id, |h| {
+            let (routes, dns) = setup.take().expect("set up once");
+            h.interface = Some(interface.clone());
+            h.routes = Some(routes);
+            h.dns = dns;
+            Ok(())
+        });
+        if let Err(e) = registered {
+            self.blocking(move |_| drop(setup)).await;
+            return Err(e);
+        }
+        if let Err(e) = self.update_kill_switch().await {
+            let setup = self.with_tunnel(id, |h| {
+                h.interface = None;
+                Ok((h.routes.take(), h.dns.take()))
+            });
+            self.blocking(move |_| drop(setup)).await;
+            return Err(e);
+        }
+
+        let manager = self.clone();
+        self.with_tunnel(id, |h| {
+            let shutdown = h.shutdown.clone();
+            let meter = Arc::clone(&h.meter);
+            let config = config.clone();
+            h.task = Some(tokio::spawn(async move {
+                let result = manager
+                    .supervise(id, &mut tun, socket, &config, keys, &meter, &shutdown)
+                    .await;
+                if let Err(e) = result {
+                    eprintln!("packet loop stopped: {e}");
+                    manager.set_state(id, State::Failed(format!("{e:#}")));
+                }
+            }));
+            self.mark_connected(h, rtt);
+            Ok(())
+        })
+    }
+
+    /// Run the packet loop, re-establishing the session whenever it is lost.
+    ///
+    /// Each reconnect opens a new socket, in case the local address changed, and
+    /// runs a fresh handshake.  Failed attempts are retried after a jittered
+    /// exponential [`Backoff`](crate::backoff::Backoff) capped at
+    /// `config.reconnect_max_delay_secs`; the attempt count and the time of the
+    /// next attempt are reported through [`TunnelStatus::Reconnecting`].  Returns
+    /// once `shutdown` fires, or with an error if the TUN device fails.
+    #[allow(clippy::too_many_arguments)]
+    async fn supervise(
+        &self,
+        id: u64,
+        tun: &mut P::Tun,
+        mut socket: T::Socket,
+        config: &VpnConfig,
+        mut keys: crate::handshake::TransportKeys,
+        meter: &Mutex<Meter>,
+        shutdown: &CancellationToken,
+    ) -> Result<()> {
+        use crate::backoff::{Backoff, DEFAULT_MAX_DELAY};
+        use crate::stats::StatsRecorder;
+        use crate::tunnel::Exit;
+
+        let max_delay = config
+            .reconnect_max_delay_secs
+            .map_or(DEFAULT_MAX_DELAY, Duration::from_secs);
+        let mut backoff = Backoff::new(max_delay);
+        // Disconnected already if the handle is gone.
+        let Ok(name) = self.with_tunnel(id, |h| Ok(h.name.clone())) else {
+            return Ok(());
+        };
+        let recorder = StatsRecorder::new(&name, meter, &self.inner.clock, &self.inner.events);
+
+        loop {
+            let exit =
+                crate::tunnel::run(tun, socket, config, keys, &recorder, shutdown.clone()).await?;
+            let reason = match exit {
+                Exit::Shutdown => return Ok(()),
+                Exit::SessionLost(reason) => reason,
+            };
+            eprintln!("session lost: {reason}; reconnecting");
+            self.announce(VpnEvent::Error {
+                name: name.clone(),
+                message: format!("session lost: {reason}"),
+            });
+
+            let (new_socket, (new_keys, rtt)) = loop {
+                let delay = backoff.next_delay();
+                self.set_state(
+                    id,
+                    State::Reconnecting {
+                        attempt: backoff.attempt(),
+                        next_attempt: Some(self.inner.clock.now() + delay),
+                    },
+                );
+                tokio::select! {
+                    _ = tokio::time::sleep(delay) => {}
+                    _ = shutdown.cancelled() => return Ok(()),
+                }
+
+                self.set_state(
+                    id,
+                    State::Reconnecting {
+                        attempt: backoff.attempt(),
+                        next_attempt: None,
+                    },
+                );
+                let attempt = async {
+                    let socket = self.inner.transport.connect(config.server_addr).await?;
+                    let handshake = crate::tunnel::handshake(&socket, config).await?;
+                    anyhow::Ok((socket, handshake))
+                };
+                let result = tokio::select! {
+                    result = attempt => result,
+                    _ = shutdown.cancelled() => return Ok(()),
+                };
+                match result {
+                    Ok(session) => break session,
+                    Err(e) => {
+                        eprintln!("reconnect attempt {} failed: {e}", backoff.attempt());
+                        self.announce(VpnEvent::Error {
+                            name: name.clone(),
+                            message: format!(
+                                "reconnect attempt {} failed: {e:#}",
+                                backoff.attempt()
+                            ),
+                        });
+                    }
+                }
+            };
+
+            (socket, keys) = (new_socket, new_keys);
+            backoff.reset();
+            let _ = self.with_tunnel(id, |h| {
+                self.mark_connected(h, rtt);
+                Ok(())
+            });
+        }
+    }
+
+    /// Set the state of the tunnel with handle `id`, if it is still active.
+    fn set_state(&self, id: u64, state: State) {
+        let _ = self.with_tunnel(id, |h| {
+            self.transition(h, state);
+            Ok(())
+        });
+    }
+
+    /// Tear down the tunnel called `name`, or abandon it while it is still
+    /// connecting.
+    ///
+    /// Stops the packet loop and waits for it to release the TUN device, then
+    /// lifts the kill switch block for this tunnel; it is removed entirely
+    /// with the last one.
+    pub async fn disconnect(&self, name: &str) -> Result<()> {
+        let stranded = std::mem::take(&mut self.inner.firewall.lock().await.stranded);
+        let handle = self.lock().handles.remove(name);
+        let handle = match handle {
+            Some(handle) => handle,
+            // After a crash the user disconnects to lift the kept block.
+            None if stranded => return self.update_kill_switch().await,
+            None => bail!("tunnel {name:?} is not connected"),
+        };
+        self.announce(VpnEvent::StatusChanged {
+            name: name.to_string(),
+            status: TunnelStatus::Disconnected,
+        });
+
+        handle.teardown().await;
+
+        self.update_kill_switch().await
+    }
+
+    /// Tear down every tunnel, as when the daemon is asked to stop.
+    pub async fn shutdown(&self) {
+        let names: Vec<String> = self.lock().handles.keys().cloned().collect();
+        for name in names {
+            if let Err(e) = self.disconnect(&name).await {
+                eprintln!("failed to disconnect {name:?}: {e}");
+            }
+        }
+    }
+
+    /// Turn the kill switch on or off.
+    ///
+    /// Applies to the existing tunnels immediately, and to every later
+    /// [`connect`](Self::connect).  The setting is saved, so it also applies
+    /// after the daemon restarts.
+    pub async fn set_kill_switch(&self, enabled: bool) -> Result<()> {
+        self.blocking(move |platform| platform.save_kill_switch(enabled))
+            .await?;
+        self.inner.kill_switch.store(enabled, Ordering::SeqCst);
+        self.update_kill_switch().await
+    }
+
+    /// Whether the kill switch is on.
+    pub fn kill_switch(&self) -> bool {
+        self.inner.kill_switch.load(Ordering::SeqCst)
+    }
+
+    /// Return the status of the tunnel called `name` without blocking.
+    pub fn status(&self, name: &str) -> TunnelStatus {
+        self.lock()
+            .handles
+            .get(name)
+            .map_or(TunnelStatus::Disconnected, TunnelHandle::status)
+    }
+
+    /// Return transfer statistics for the tunnel called `name`.
+    ///
+    /// Returns zeroed stats when it is not connected.
+    pub fn stats(&self, name: &str) -> TunnelStats {
+        self.lock()
+            .handles
+            .get(name)
+            .map(TunnelHandle::stats)
+            .unwrap_or_default()
+    }
+
+    /// Return the status and statistics of every tunnel, ordered by name.
+    pub fn tunnels(&self) -> Vec<TunnelInfo> {
+        self.lock()
+            .handles
+            .iter()
+            .map(|(name, h)| TunnelInfo {
+                name: name.clone(),
+                status: h.status(),
+                stats: h.stats(),
+            })
+            .collect()
+    }
+
+    /// Undo system changes left behind by a previous daemon that did not shut
+    /// down cleanly.  Call once at startup, before accepting commands.
+    ///
+    /// If that daemon had the kill switch on, its tunnels dropped
+    /// unexpectedly, so traffic stays blocked until the user disconnects or
+    /// turns the kill switch off.
+    pub async fn recover(&self) -> Result<()> {
+        if let Some(kill_switch) = self.blocking(|platform| platform.recover()).await? {
+            let mut firewall = self.inner.firewall.lock().await;
+            firewall.kill_switch = Some(kill_switch);
+            firewall.stranded = true;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::handshake::{self, NO_PRESHARED_KEY};
+    use crate::keys::PrivateKey;
+    use crate::transport::Datagram;
+    use crate::wire::Message;
+    use std::io;
+    use std::net::{IpAddr, SocketAddr};
+    use tokio::io::DuplexStream;
+    use tokio::sync::mpsc;
+    use tokio_util::codec::Framed;
+    use tun::TunPacketCodec;
+
+    /// A server that answers handshakes over in-memory sockets, or ignores
+    /// everything while `silent`.
+    struct MemoryTransport {
+        key: PrivateKey,
+        silent: Arc<AtomicBool>,
+    }
+
+    struct MemorySocket {
+        to_server: mpsc::UnboundedSender<Vec<u8>>,
+        from_server: tokio::sync::Mutex<mpsc::UnboundedReceiver<Vec<u8>>>,
+    }
+
+    impl Datagram for MemorySocket {
+        async fn send(&self, buf: &[u8]) -> io::Result<usize> {
+            self.to_server
+                .send(buf.to_vec())
+                .map_err(|_| io::Error::from(io::ErrorKind::ConnectionReset))?;
+            Ok(buf.len())
+        }
+
+        async fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+            let datagram = self.from_server.lock().await.recv().await;
+            let datagram = datagram.ok_or(io::ErrorKind::ConnectionReset)?;
+            buf[..datagram.len()].copy_from_slice(&datagram);
+            Ok(datagram.len())
+        }
+    }
+
+    impl Transport for MemoryTransport {
+        type Socket = MemorySocket;
+
+        async fn connect(&self, _server: SocketAddr) -> io::Result<MemorySocket> {
+            let (to_server, mut requests) = mpsc::unbounded_channel::<Vec<u8>>();
+            let (replies, from_server) = mpsc::unbounded_channel();
+            let key = *self.key.as_bytes();
+            let silent = Arc::clone(&self.silent);
+
+            tokio::spawn(async move {
+                while let Some(datagram) = requests.recv().await {
+                    let Some(Message::HandshakeInit { sender, payload }) =
+                        Message::parse(&datagram)
+                    else {
+                        continue;
+                    };
+                    if silent.load(Ordering::Relaxed) {
+                        continue;
+                    }
+                    let accepted = handshake::respond(
+                        &key,
+                        sender,
+                        payload,
+                        |_| Some(NO_PRESHARED_KEY),
+                        |_| false,
+                    )
+                    .unwrap();
+                    let response = Message::HandshakeResponse {
+                        sender: accepted.keys.local_index,
+                        receiver: sender,
+                        payload: &accepted.response,
+                    };
+                    let _ = replies.send(response.encode());
+                }
+            });
+
+            Ok(MemorySocket {
+                to_server,
+                from_server: tokio::sync::Mutex::new(from_server),
+            })
+        }
+    }
+
+    /// Reads the same time forever.
+    struct FixedClock(SystemTime);
+
+    impl Clock for FixedClock {
+        fn now(&self) -> SystemTime {
+            self.0
+        }
+    }
+
+    /// What a [`MemoryPlatform`] has changed, for tests to inspect.
+    #[derive(Default)]
+    struct Host {
+        /// Routed networks, by interface.
+        routes: BTreeMap<String, Vec<Cidr>>,
+        /// The interface the resolver points at, with its servers.
+        dns: Option<(String, Vec<IpAddr>)>,
+        kill_switch: Option<Allowed>,
+        saved_kill_switch: bool,
+        /// Whether a crashed daemon left a kill switch block behind.
+        leftover_block: bool,
+        /// The far ends of the TUN devices, kept open while the test runs.
+        devices: Vec<DuplexStream>,
+    }
+
+    #[derive(Clone, Default)]
+    struct MemoryPlatform(Arc<Mutex<Host>>);
+
+    impl MemoryPlatform {
+        fn host(&self) -> MutexGuard<'_, Host> {
+            self.0.lock().unwrap()
+        }
+    }
+
+    /// Runs its closure when dropped, undoing a change to the [`Host`].
+    struct Undo(Option<Box<dyn FnOnce() + Send>>);
+
+    impl Drop for Undo {
+        fn drop(&mut self) {
+            if let Some(undo) = self.0.take() {
+                undo();
+            }
+        }
+    }
+
+    impl Platform for MemoryPlatform {
+        type Tun = Framed<DuplexStream, TunPacketCodec>;
+        type Routes = Undo;
+        type Dns = Undo;
+        type KillSwitch = Undo;
+
+        fn create_tun(&self, config: &VpnConfig) -> Result<(Self::Tun, String)> {
+            let mut host = self.host();
+            let name = config
+                .interface_name
+                .clone()
+                .unwrap_or_else(|| format!("tun{}", host.devices.len()));
+            let (ours, theirs) = tokio::io::duplex(4096);
+            host.devices.push(theirs);
+            Ok((Framed::new(ours, TunPacketCodec::new(false, 1500)), name))
+        }
+
+        fn install_routes(
+            &self,
+            interface: &str,
+            _server: IpAddr,
+            allowed_ips: &[Cidr],
+        ) -> Result<Undo> {
+            self.host()
+                .routes
+                .insert(interface.to_string(), allowed_ips.to_vec());
+            let platform = self.clone();
+            let interface = interface.to_string();
+            Ok(Undo(Some(Box::new(move || {
+                platform.host().routes.remove(&interface);
+            }))))
+        }
+
+        fn apply_dns(&self, interface: &str, servers: &[IpAddr]) -> Result<Undo> {
+            self.host().dns = Some((interface.to_string(), servers.to_vec()));
+            let platform = self.clone();
+            Ok(Undo(Some(Box::new(move || platform.host().dns = None))))
+        }
+
+        fn enable_kill_switch(&self, allowed: Allowed) -> Result<Undo> {
+            self.host().kill_switch = Some(allowed);
+            let platform = self.clone();
+            Ok(Undo(Some(Box::new(move || {
+                platform.host().kill_switch = None
+            }))))
+        }
+
+        fn update_kill_switch(&self, _kill_switch: &mut Undo, allowed: Allowed) -> Result<()> {
+            self.host().kill_switch = Some(allowed);
+            Ok(())
+        }
+
+        fn saved_kill_switch(&self) -> bool {
+            self.host().saved_kill_switch
+        }
+
+        fn save_kill_switch(&self, enabled: bool) -> Result<()> {
+            self.host().saved_kill_switch = enabled;
+            Ok(())
+        }
+
+        fn recover(&self) -> Result<Option<Undo>> {
+            let leftover = std::mem::take(&mut self.host().leftover_block);
+            if leftover && self.saved_kill_switch() {
+                return self.enable_kill_switch(Allowed::default()).map(Some);
+            }
+            self.host().kill_switch = None;
+            Ok(None)
+        }
+    }
+
+    type TestManager = VpnManager<MemoryTransport, FixedClock, MemoryPlatform>;
+
+    const SINCE: Duration = Duration::from_secs(1_700_000_000);
+
+    /// A manager on its own host, with a server that answers when `answers`,
+    /// and that server's key.
+    fn manager(answers: bool) -> (TestManager, MemoryPlatform, PrivateKey) {
+        let key = PrivateKey::generate();
+        let transport = MemoryTransport {
+            key: PrivateKey::from_bytes(*key.as_bytes()),
+            silent: Arc::new(AtomicBool::new(!answers)),
+        };
+        let platform = MemoryPlatform::default();
+        let clock = FixedClock(SystemTime::UNIX_EPOCH + SINCE);
+        let manager = VpnManager::with_platform(transport, clock, platform.clone());
+        (manager, platform, key)
+    }
+
+    fn config(server: &PrivateKey, port: u16, allowed_ips: &[&str], dns: &[&str]) -> VpnConfig {
+        VpnConfig {
+            server_addr: SocketAddr::from(([203, 0, 113, 1], port)),
+            server_public_key: server.public_key().to_base64(),
+            client_private_key: PrivateKey::generate().to_base64().to_string(),
+            client_ips: vec!["10.8.0.2".parse().unwrap()],
+            prefix_len: None,
+            prefix_len_v6: None,
+            mtu: None,
+            interface_name: None,
+            dns_servers: dns.iter().map(|ip| ip.parse().unwrap()).collect(),
+            allowed_ips: allowed_ips.iter().map(|net| net.to_string()).collect(),
+            preshared_key: None,
+            reconnect_max_delay_secs: None,
+            persistent_keepalive_secs: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn connect_sets_up_the_tunnel_and_disconnect_undoes_it() {
+        let (manager, platform, key) = manager(true);
+        let config = config(&key, 51820, &["0.0.0.0/0"], &["10.8.0.1"]);
+        manager.connect("default", config).await.unwrap();
+
+        assert_eq!(
+            manager.status("default"),
+            TunnelStatus::Connected {
+                since: SystemTime::UNIX_EPOCH + SINCE,
+                server: "203.0.113.1:51820".to_string(),
+            }
+        );
+        assert!(manager.stats("default").last_handshake.is_some());
+        {
+            let host = platform.host();
+            assert_eq!(host.routes.keys().collect::<Vec<_>>(), ["tun0"]);
+            assert_eq!(
+                host.dns,
+                Some(("tun0".to_string(), vec!["10.8.0.1".parse().unwrap()]))
+            );
+        }
+
+        manager.disconnect("default").await.unwrap();
+        assert_eq!(manager.status("default"), TunnelStatus::Disconnected);
+        let host = platform.host();
+        assert!(host.routes.is_empty());
+        assert!(host.dns.is_none());
+    }
+
+    #[tokio::test]
+    async fn managers_do_not_share_tunnels() {
+        let (first, first_host, first_key) = manager(true);
+        let (second, second_host, second_key) = manager(true);
+        let config = |key| config(key, 51820, &["0.0.0.0/0"], &["10.8.0.1"]);
+
+        first.connect("default", config(&first_key)).await.unwrap();
+        second
+            .connect("default", config(&second_key))
+            .await
+            .unwrap();
+        first.disconnect("default").await.unwrap();
+
+        assert_eq!(first.status("default"), TunnelStatus::Disconnected);
+        assert!(matches!(
+            second.status("default"),
+            TunnelStatus::Connected { .. }
+        ));
+        assert!(first_host.host().routes.is_empty());
+        assert_eq!(second_host.host().routes.len(), 1);
+
+        second.disconnect("default").await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn conflicting_tunnels_are_refused() {
+        let (manager, platform, key) = manager(true);
+        manager
+            .connect("a", config(&key, 1, &["10.0.0.0/8"], &["10.8.0.1"]))
+            .await
+            .unwrap();
+
+        let overlapping = config(&key, 2, &["10.1.0.0/16"], &[]);
+        let err = manager.connect("b", overlapping).await.unwrap_err();
+        assert!(err.to_string().contains("overlap"), "{err}");
+
+        let second_dns = config(&key, 2, &["192.168.0.0/16"], &["10.9.0.1"]);
+        let err = manager.connect("b", second_dns).await.unwrap_err();
+        assert!(err.to_string().contains("DNS"), "{err}");
+
+        let duplicate = config(&key, 2, &["192.168.0.0/16"], &[]);
+        assert!(manager.connect("a", duplicate).await.is_err());
+
+        assert_eq!(manager.tunnels().len(), 1);
+        assert_eq!(platform.host().routes.len(), 1);
+        manager.disconnect("a").await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn kill_switch_lets_through_every_live_tunnel() {
+        let (manager, platform, key) = manager(true);
+        manager.set_kill_switch(true).await.unwrap();
+        assert!(platform.host().saved_kill_switch);
+        // Nothing to protect yet.
+        assert!(platform.host().kill_switch.is_none());
+
+        let a = config(&key, 1, &["10.0.0.0/8"], &[]);
+        let b = config(&key, 2, &["192.168.0.0/16"], &[]);
+        manager.connect("a", a.clone()).await.unwrap();
+        manager.connect("b", b.clone()).await.unwrap();
+        assert_eq!(
+            platform.host().kill_switch,
+            Some(Allowed {
+                servers: vec![a.server_addr, b.server_addr],
+                interfaces: vec!["tun0".to_string(), "tun1".to_string()],
+            })
+        );
+
+        manager.disconnect("a").await.unwrap();
+        assert_eq!(
+            platform.host().kill_switch,
+            Some(Allowed {
+                servers: vec![b.server_addr],
+                interfaces: vec!["tun1".to_string()],
+            })
+        );
+
+        manager.disconnect("b").await.unwrap();
+        assert!(platform.host().kill_switch.is_none());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn unanswered_handshake_fails_the_tunnel() {
+        let (manager, platform, key) = manager(false);
+        manager.set_kill_switch(true).await.unwrap();
+
+        let err = manager
+            .connect("default", config(&key, 1, &["0.0.0.0/0"], &[]))
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("timed out"), "{err}");
+
+        assert!(matches!(manager.status("default"), TunnelStatus::Error(_)));
+        let host = platform.host();
+        assert!(host.routes.is_empty());
+        assert!(host.kill_switch.is_none());
+    }
+
+    #[tokio::test]
+    async fn subscribers_see_the_tunnel_connect() {
+        let (manager, _platform, key) = manager(true);
+        let mut events = manager.subscribe();
+        manager
+            .connect("default", config(&key, 1, &["0.0.0.0/0"], &[]))
+            .await
+            .unwrap();
+
+        let mut next = || match events.try_recv().unwrap() {
+            VpnEvent::StatusChanged { name, status } => (name, Some(status)),
+            VpnEvent::HandshakeCompleted { name, .. } => (name, None),
+            other => panic!("unexpected event {other:?}"),
+        };
+        assert_eq!(
+            next(),
+            ("default".to_string(), Some(TunnelStatus::Connecting))
+        );
+        assert!(matches!(next(), (_, Some(TunnelStatus::Connected { .. }))));
+        assert_eq!(next(), ("default".to_string(), None));
+
+        manager.disconnect("default").await.unwrap();
+    }
+
+    /// The next status change of a tunnel, or the message of the next error.
+    async fn next_status(
+        events: &mut broadcast::Receiver<VpnEvent>,
+    ) -> Result<TunnelStatus, String> {
+        loop {
+            match events.recv().await.unwrap() {
+                VpnEvent::StatusChanged { status, .. } => return Ok(status),
+                VpnEvent::Error { message, .. } => return Err(message),
+                _ => {}
+            }
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_lost_session_is_reconnected() {
+        let (manager, _platform, key) = manager(true);
+        manager
+            .connect("default", config(&key, 1, &["0.0.0.0/0"], &[]))
+            .await
+            .unwrap();
+        let server = "203.0.113.1:1".to_string();
+        let mut events = manager.subscribe();
+
+        // The server stops answering, so the first rekey goes unanswered.
+        manager
+            .inner
+            .transport
+            .silent
+            .store(true, Ordering::Relaxed);
+        assert_eq!(
+            next_status(&mut events).await,
+            Err("session lost: no handshake response from 203.0.113.1:1".to_string())
+        );
+        for attempt in [1, 2] {
+            let Ok(TunnelStatus::Reconnecting {
+                attempt: reported,
+                next_attempt: Some(next_attempt),
+                ..
+            }) = next_status(&mut events).await
+            else {
+                panic!("attempt {attempt} was not scheduled");
+            };
+            assert_eq!(reported, attempt);
+            let delay = next_attempt
+                .duration_since(SystemTime::UNIX_EPOCH + SINCE)
+                .unwrap();
+            assert!(delay <= crate::backoff::INITIAL_DELAY * (1 << (attempt - 1)));
+            assert_eq!(
+                manager.status("default"),
+                TunnelStatus::Reconnecting {
+                    server: server.clone(),
+                    attempt,
+                    next_attempt: Some(next_attempt),
+                }
+            );
+            assert_eq!(
+                next_status(&mut events).await,
+                Ok(TunnelStatus::Reconnecting {
+                    server: server.clone(),
+                    attempt,
+                    next_attempt: None,
+                })
+            );
+            if attempt == 1 {
+                let error = next_status(&mut events).await.unwrap_err();
+                assert!(error.starts_with("reconnect attempt 1 failed"), "{error}");
+                manager
+                    .inner
+                    .transport
+                    .silent
+                    .store(false, Ordering::Relaxed);
+            }
+        }
+
+        let connected = TunnelStatus::Connected {
+            since: SystemTime::UNIX_EPOCH + SINCE,
+            server,
+        };
+        assert_eq!(next_status(&mut events).await, Ok(connected.clone()));
+        assert_eq!(manager.status("default"), connected);
+
+        manager.disconnect("default").await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn block_left_by_a_crash_stays_until_disconnect() {
+        let (manager, platform, _key) = manager(true);
+        {
+            let mut host = platform.host();
+            host.saved_kill_switch = true;
+            host.leftover_block = true;
+        }
+
+        manager.recover().await.unwrap();
+        assert_eq!(platform.host().kill_switch, Some(Allowed::default()));
 
-    if let Some(h) = &*guard {
-        let mut stats = h.stats.lock().unwrap_or_else(|p| p.into_inner());
-        stats.bytes_sent += bytes_sent;
-        stats.bytes_received += bytes_received;
-        stats.last_handshake = Some(SystemTime::now());
+        manager.disconnect("default").await.unwrap();
+        assert!(platform.host().kill_switch.is_none());
+        assert!(manager.disconnect("default").await.is_err());
     }
 }
@@ -0,0 +1,173 @@
+//! On-the-wire message framing shared by the client tunnel and the relay server.
+//!
+//! Every UDP datagram starts with a one-byte message type followed by
+//! little-endian session indices, loosely following WireGuard's layout:
+//!
+//! ```text
+//! handshake init:     [1][sender u32][noise message]
+//! handshake response: [2][sender u32][receiver u32][noise message]
+//! transport data:     [4][receiver u32][counter u64][ciphertext]
+//! ```
+
+pub const MSG_HANDSHAKE_INIT: u8 = 1;
+pub const MSG_HANDSHAKE_RESPONSE: u8 = 2;
+pub const MSG_TRANSPORT: u8 = 4;
+
+/// A parsed datagram borrowing its payload from the receive buffer.
+#[derive(Debug)]
+pub enum Message<'a> {
+    HandshakeInit {
+        sender: u32,
+        payload: &'a [u8],
+    },
+    HandshakeResponse {
+        sender: u32,
+        receiver: u32,
+        payload: &'a [u8],
+    },
+    Transport {
+        receiver: u32,
+        /// Per-direction message counter; doubles as the AEAD nonce.
+        counter: u64,
+        ciphertext: &'a [u8],
+    },
+}
+
+impl<'a> Message<'a> {
+    /// Parse a datagram. Returns `None` for unknown types or truncated input.
+    pub fn parse(buf: &'a [u8]) -> Option<Self> {
+        let (&kind, rest) = buf.split_first()?;
+
+        match kind {
+            MSG_HANDSHAKE_INIT => {
+                let (sender, payload) = read_u32(rest)?;
+                Some(Message::HandshakeInit { sender, payload })
+            }
+            MSG_HANDSHAKE_RESPONSE => {
+                let (sender, rest) = read_u32(rest)?;
+                let (receiver, payload) = read_u32(rest)?;
+                Some(Message::HandshakeResponse {
+                    sender,
+                    receiver,
+                    payload,
+                })
+            }
+            MSG_TRANSPORT => {
+                let (receiver, rest) = read_u32(rest)?;
+                if rest.len() < 8 {
+                    return None;
+                }
+                let (counter, ciphertext) = rest.split_at(8);
+                Some(Message::Transport {
+                    receiver,
+                    counter: u64::from_le_bytes(counter.try_into().ok()?),
+                    ciphertext,
+                })
+            }
+            _ => None,
+        }
+    }
+
+    /// Serialize the message into a new datagram buffer.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+
+        match self {
+            Message::HandshakeInit { sender, payload } => {
+                out.push(MSG_HANDSHAKE_INIT);
+                out.extend_from_slice(&sender.to_le_bytes());
+                out.extend_from_slice(payload);
+            }
+            Message::HandshakeResponse {
+                sender,
+                receiver,
+                payload,
+            } => {
+                out.push(MSG_HANDSHAKE_RESPONSE);
+                out.extend_from_slice(&sender.to_le_bytes());
+                out.extend_from_slice(&receiver.to_le_bytes());
+                out.extend_from_slice(payload);
+            }
+            Message::Transport {
+                receiver,
+                counter,
+                ciphertext,
+            } => {
+                out.push(MSG_TRANSPORT);
+                out.extend_from_slice(&receiver.to_le_bytes());
+                out.extend_from_slice(&counter.to_le_bytes());
+                out.extend_from_slice(ciphertext);
+            }
+        }
+
+        out
+    }
+}
+
+/// Split a little-endian `u32` off the front of `buf`.
+fn read_u32(buf: &[u8]) -> Option<(u32, &[u8])> {
+    if buf.len() < 4 {
+        return None;
+    }
+    let (head, rest) = buf.split_at(4);
+    Some((u32::from_le_bytes(head.try_into().ok()?), rest))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn messages_round_trip() {
+        let transport = Message::Transport {
+            receiver: 0xdead_beef,
+            counter: 1 << 40,
+            ciphertext: b"sealed",
+        }
+        .encode();
+        assert_eq!(transport[0], MSG_TRANSPORT);
+        assert!(matches!(
+            Message::parse(&transport),
+            Some(Message::Transport { receiver: 0xdead_beef, counter, ciphertext: b"sealed" })
+                if counter == 1 << 40
+        ));
+
+        let response = Message::HandshakeResponse {
+            sender: 1,
+            receiver: 2,
+            payload: b"noise",
+        }
+        .encode();
+        assert!(matches!(
+            Message::parse(&response),
+            Some(Message::HandshakeResponse {
+                sender: 1,
+                receiver: 2,
+                payload: b"noise"
+            })
+        ));
+
+        let init = Message::HandshakeInit {
+            sender: 7,
+            payload: &[],
+        }
+        .encode();
+        assert!(matches!(
+            Message::parse(&init),
+            Some(Message::HandshakeInit {
+                sender: 7,
+                payload: []
+            })
+        ));
+    }
+
+    #[test]
+    fn truncated_and_unknown_datagrams_are_rejected() {
+        assert!(Message::parse(&[]).is_none());
+        assert!(Message::parse(&[3, 0, 0, 0, 0]).is_none());
+        assert!(Message::parse(&[MSG_HANDSHAKE_INIT, 1, 2, 3]).is_none());
+        assert!(Message::parse(&[MSG_HANDSHAKE_RESPONSE, 1, 0, 0, 0, 2, 0, 0]).is_none());
+        // Receiver index, then one byte short of the counter.
+        assert!(Message::parse(&[MSG_TRANSPORT, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]).is_none());
+    }
+}
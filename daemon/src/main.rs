@@ -1,21 +1,41 @@
 //! NySVPN privileged background daemon.
 //!
 //! Listens on a Unix domain socket at [`shared::SOCKET_PATH`] and handles
-//! [`VpnCommand`] messages from the client (CLI / GUI Tauri backend).
+//! [`VpnCommand`] messages from the client (CLI / GUI Tauri backend), each
+//! wrapped in a [`ClientMessage::Request`] after a versioned hello.
 //!
 //! On macOS this process is installed as a LaunchDaemon so it runs as root,
 //! which is required to create TUN network interfaces.
 
 use anyhow::Result;
-use shared::{VpnCommand, VpnResponse, SOCKET_PATH};
+use nysvpn_core::vpn::VpnManager;
+use serde::Serialize;
+use shared::{
+    ClientMessage, DaemonMessage, VpnCommand, VpnEvent, VpnResponse, MIN_PROTOCOL_VERSION,
+    PROTOCOL_VERSION, SOCKET_PATH,
+};
 use std::path::Path;
-use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader, Lines};
+use tokio::net::unix::{OwnedReadHalf, OwnedWriteHalf};
 use tokio::net::UnixListener;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::broadcast::error::RecvError;
+
+/// How often subscribers get a [`VpnEvent::Stats`] for each tunnel.
+const STATS_INTERVAL: Duration = Duration::from_secs(1);
 
 #[tokio::main]
 async fn main() -> Result<()> {
     println!("NySVPN daemon starting – socket: {SOCKET_PATH}");
 
+    // Restore routes, DNS and firewall rules a crashed daemon may have left
+    // pointing at a dead tunnel.
+    let manager = VpnManager::new();
+    if let Err(e) = manager.recover().await {
+        eprintln!("failed to restore previous network state: {e}");
+    }
+
     // Remove stale socket file if present.
     if Path::new(SOCKET_PATH).exists() {
         std::fs::remove_file(SOCKET_PATH)?;
@@ -24,53 +44,285 @@ async fn main() -> Result<()> {
     let listener = UnixListener::bind(SOCKET_PATH)?;
     println!("NySVPN daemon ready");
 
+    // Stopping the service sends SIGTERM; without a handler the tunnels'
+    // routes, DNS and firewall rules would be left in place.
+    let mut sigint = signal(SignalKind::interrupt())?;
+    let mut sigterm = signal(SignalKind::terminate())?;
+
     loop {
-        let (stream, _addr) = listener.accept().await?;
-        tokio::spawn(async move {
-            if let Err(e) = handle_client(stream).await {
-                eprintln!("client handler error: {e}");
+        tokio::select! {
+            _ = sigint.recv() => break,
+            _ = sigterm.recv() => break,
+
+            accepted = listener.accept() => {
+                let (stream, _addr) = accepted?;
+                let manager = manager.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = handle_client(stream, &manager).await {
+                        eprintln!("client handler error: {e}");
+                    }
+                });
             }
-        });
+        }
     }
+
+    println!("NySVPN daemon shutting down");
+    manager.shutdown().await;
+    let _ = std::fs::remove_file(SOCKET_PATH);
+    Ok(())
 }
 
-/// Handle a single client connection: read newline-delimited JSON commands,
-/// dispatch them, and write back a JSON response.
-async fn handle_client(stream: tokio::net::UnixStream) -> Result<()> {
+/// Handle a single client connection: agree on a protocol version, then
+/// read newline-delimited [`ClientMessage::Request`]s, dispatch them, and
+/// write back a [`DaemonMessage::Response`] for each.  After
+/// [`VpnCommand::Subscribe`] the connection carries events instead.
+async fn handle_client(stream: tokio::net::UnixStream, manager: &VpnManager) -> Result<()> {
     let (read_half, mut write_half) = stream.into_split();
     let mut lines = BufReader::new(read_half).lines();
+    let mut greeted = false;
 
     while let Some(line) = lines.next_line().await? {
         if line.trim().is_empty() {
             continue;
         }
 
-        let response = match serde_json::from_str::<VpnCommand>(&line) {
-            Ok(cmd) => dispatch(cmd),
-            Err(e) => VpnResponse::Error(format!("parse error: {e}")),
+        let message = match serde_json::from_str::<ClientMessage>(&line) {
+            Ok(message) => message,
+            Err(_) if is_unversioned(&line) => {
+                // A client from before versioning only understands a bare
+                // response, so tell it that way and hang up.
+                let response = VpnResponse::Error(format!(
+                    "client is too old for this daemon (protocol {MIN_PROTOCOL_VERSION} or newer \
+                     required); please upgrade it"
+                ));
+                return send(&mut write_half, &response).await;
+            }
+            Err(e) => {
+                let reply = match request_id(&line) {
+                    Some(id) => DaemonMessage::Response {
+                        id,
+                        response: VpnResponse::Error(format!("unsupported request: {e}")),
+                    },
+                    None => DaemonMessage::Error(format!("parse error: {e}")),
+                };
+                send(&mut write_half, &reply).await?;
+                continue;
+            }
         };
 
-        let mut json = serde_json::to_string(&response)?;
-        json.push('\n');
-        write_half.write_all(json.as_bytes()).await?;
+        match message {
+            ClientMessage::Hello {
+                min_version,
+                max_version,
+            } => {
+                if greeted {
+                    let reply = DaemonMessage::Error("hello already received".to_string());
+                    send(&mut write_half, &reply).await?;
+                    continue;
+                }
+                match negotiate(min_version, max_version) {
+                    Some(version) => {
+                        greeted = true;
+                        send(&mut write_half, &DaemonMessage::Welcome { version }).await?;
+                    }
+                    None => {
+                        let reply = DaemonMessage::Unsupported {
+                            min_version: MIN_PROTOCOL_VERSION,
+                            max_version: PROTOCOL_VERSION,
+                        };
+                        return send(&mut write_half, &reply).await;
+                    }
+                }
+            }
+
+            ClientMessage::Request { id, .. } if !greeted => {
+                let response = VpnResponse::Error("expected a hello first".to_string());
+                send(&mut write_half, &DaemonMessage::Response { id, response }).await?;
+            }
+
+            ClientMessage::Request {
+                id,
+                command: VpnCommand::Subscribe,
+            } => {
+                let reply = DaemonMessage::Response {
+                    id,
+                    response: VpnResponse::Ok,
+                };
+                send(&mut write_half, &reply).await?;
+                return stream_events(manager, &mut write_half, &mut lines).await;
+            }
+
+            ClientMessage::Request { id, command } => {
+                let response = dispatch(manager, command).await;
+                send(&mut write_half, &DaemonMessage::Response { id, response }).await?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Whether `line` is a bare command from a client built before protocol
+/// versioning: a unit variant such as `"GetStatus"`, or an object keyed by a
+/// variant name, such as `{"Connect":{..}}`, rather than by a
+/// [`ClientMessage`] one.
+///
+/// The shape is checked rather than the contents, as those old commands no
+/// longer parse as a [`VpnCommand`].
+fn is_unversioned(line: &str) -> bool {
+    match serde_json::from_str::<serde_json::Value>(line) {
+        Ok(serde_json::Value::String(_)) => true,
+        Ok(serde_json::Value::Object(map)) => {
+            map.len() == 1 && map.keys().all(|key| key != "Hello" && key != "Request")
+        }
+        _ => false,
+    }
+}
+
+/// The newest version both the daemon and a client speaking
+/// `min_version..=max_version` understand, if any.
+fn negotiate(min_version: u32, max_version: u32) -> Option<u32> {
+    let version = max_version.min(PROTOCOL_VERSION);
+    (version >= min_version.max(MIN_PROTOCOL_VERSION)).then_some(version)
+}
+
+/// The id of a request the daemon cannot parse, such as one carrying a
+/// command added after this build, so the error can still be matched to it.
+fn request_id(line: &str) -> Option<u64> {
+    let value: serde_json::Value = serde_json::from_str(line).ok()?;
+    value.get("Request")?.get("id")?.as_u64()
+}
+
+/// Send the subscriber every [`VpnEvent`] plus a stats tick per tunnel every
+/// [`STATS_INTERVAL`], until it hangs up.  Anything it writes is ignored.
+async fn stream_events(
+    manager: &VpnManager,
+    write_half: &mut OwnedWriteHalf,
+    lines: &mut Lines<BufReader<OwnedReadHalf>>,
+) -> Result<()> {
+    // Subscribe before taking the snapshot so no change falls in between.
+    let mut events = manager.subscribe();
+    send_statuses(manager, write_half).await?;
+
+    let mut ticker = tokio::time::interval(STATS_INTERVAL);
+    loop {
+        tokio::select! {
+            event = events.recv() => match event {
+                Ok(event) => send(write_half, &DaemonMessage::Event(event)).await?,
+                // Some events were dropped; bring the client up to date.
+                Err(RecvError::Lagged(_)) => send_statuses(manager, write_half).await?,
+                Err(RecvError::Closed) => return Ok(()),
+            },
+
+            _ = ticker.tick() => {
+                for info in manager.tunnels() {
+                    let event = VpnEvent::Stats {
+                        name: info.name,
+                        stats: info.stats,
+                    };
+                    send(write_half, &DaemonMessage::Event(event)).await?;
+                }
+            }
+
+            line = lines.next_line() => {
+                if line?.is_none() {
+                    return Ok(());
+                }
+            }
+        }
     }
+}
+
+/// Send a [`VpnEvent::StatusChanged`] with the current status of every tunnel.
+async fn send_statuses(manager: &VpnManager, write_half: &mut OwnedWriteHalf) -> Result<()> {
+    for info in manager.tunnels() {
+        let event = VpnEvent::StatusChanged {
+            name: info.name,
+            status: info.status,
+        };
+        send(write_half, &DaemonMessage::Event(event)).await?;
+    }
+    Ok(())
+}
 
+/// Write `message` as one line of JSON.
+async fn send(write_half: &mut OwnedWriteHalf, message: &impl Serialize) -> Result<()> {
+    let mut json = serde_json::to_string(message)?;
+    json.push('\n');
+    write_half.write_all(json.as_bytes()).await?;
     Ok(())
 }
 
 /// Execute a [`VpnCommand`] and return the appropriate [`VpnResponse`].
-fn dispatch(cmd: VpnCommand) -> VpnResponse {
+async fn dispatch(manager: &VpnManager, cmd: VpnCommand) -> VpnResponse {
     match cmd {
-        VpnCommand::Connect(config) => match nysvpn_core::vpn::connect(config) {
+        VpnCommand::Connect { name, config } => match manager.connect(&name, *config).await {
             Ok(()) => VpnResponse::Ok,
             Err(e) => VpnResponse::Error(e.to_string()),
         },
-        VpnCommand::Disconnect => match nysvpn_core::vpn::disconnect() {
+        VpnCommand::Disconnect { name } => match manager.disconnect(&name).await {
             Ok(()) => VpnResponse::Ok,
             Err(e) => VpnResponse::Error(e.to_string()),
         },
-        VpnCommand::GetStatus => VpnResponse::Status(nysvpn_core::vpn::get_status()),
-        VpnCommand::GetStats => VpnResponse::Stats(nysvpn_core::vpn::get_stats()),
+        VpnCommand::GetStatus { name } => VpnResponse::Status(manager.status(&name)),
+        VpnCommand::GetStats { name } => VpnResponse::Stats(manager.stats(&name)),
+        VpnCommand::ListTunnels => VpnResponse::Tunnels(manager.tunnels()),
+        VpnCommand::SetKillSwitch(enabled) => match manager.set_kill_switch(enabled).await {
+            Ok(()) => VpnResponse::Ok,
+            Err(e) => VpnResponse::Error(e.to_string()),
+        },
+        VpnCommand::GetKillSwitch => VpnResponse::KillSwitch(manager.kill_switch()),
+        VpnCommand::Subscribe => {
+            VpnResponse::Error("subscribe is handled by the connection".to_string())
+        }
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Lines as sent by clients built before protocol versioning.
+    const UNVERSIONED: &[&str] = &[
+        r#""Disconnect""#,
+        r#""GetStatus""#,
+        r#""GetStats""#,
+        r#"{"SetKillSwitch":true}"#,
+        r#"{"Connect":{"server_addr":"203.0.113.1:51820","server_public_key":"c2VydmVy","client_private_key":"Y2xpZW50","client_ip":"10.8.0.2","dns_servers":["1.1.1.1"],"allowed_ips":["0.0.0.0/0"]}}"#,
+    ];
+
+    #[test]
+    fn unversioned_commands_are_recognised() {
+        for line in UNVERSIONED {
+            assert!(
+                serde_json::from_str::<ClientMessage>(line).is_err(),
+                "{line}"
+            );
+            assert!(is_unversioned(line), "{line}");
+        }
+    }
+
+    #[test]
+    fn versioned_and_malformed_lines_are_not_unversioned() {
+        for line in [
+            r#"{"Hello":{"min_version":1,"max_version":1}}"#,
+            r#"{"Request":{"id":1,"command":"ListTunnels"}}"#,
+            r#"{"Request":{"id":2,"command":"Frobnicate"}}"#,
+            "garbage",
+            r#"{"Hello":{},"Request":{}}"#,
+        ] {
+            assert!(!is_unversioned(line), "{line}");
+        }
+    }
+
+    #[test]
+    fn negotiate_picks_newest_common_version() {
+        assert_eq!(negotiate(1, PROTOCOL_VERSION + 5), Some(PROTOCOL_VERSION));
+        assert_eq!(
+            negotiate(MIN_PROTOCOL_VERSION, MIN_PROTOCOL_VERSION),
+            Some(MIN_PROTOCOL_VERSION)
+        );
+        assert_eq!(negotiate(PROTOCOL_VERSION + 1, PROTOCOL_VERSION + 2), None);
+        assert_eq!(negotiate(0, MIN_PROTOCOL_VERSION - 1), None);
+    }
+}
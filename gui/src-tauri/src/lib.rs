@@ -2,50 +2,91 @@
 //!
 //! Exposes Tauri commands that the React frontend calls to manage the VPN tunnel.
 //! Commands communicate with the privileged daemon over the Unix socket IPC.
+//! The daemon's events are forwarded to the frontend as [`VPN_EVENT`].
 
+use futures::StreamExt;
 use serde::{ Deserialize, Serialize };
-use shared::{ ServerInfo, TunnelStats, TunnelStatus, VpnConfig };
+use shared::{ ServerInfo, TunnelInfo, TunnelStats, TunnelStatus, VpnConfig, DEFAULT_TUNNEL };
+use std::time::Duration;
+use tauri::Emitter;
 
 // ── Tauri commands ────────────────────────────────────────────────────────────
 
+// Commands that address one tunnel take its name, defaulting to
+// `DEFAULT_TUNNEL` so a frontend with a single connection can leave it out.
+
 /// Connect to a VPN server.
 #[tauri::command]
-async fn vpn_connect(config: VpnConfig) -> Result<(), String> {
+async fn vpn_connect(name: Option<String>, config: VpnConfig) -> Result<(), String> {
     let mut client = client::DaemonClient
         ::connect().await
         .map_err(|e| format!("cannot connect to daemon: {e}"))?;
 
-    client.vpn_connect(config).await.map_err(|e| e.to_string())
+    client
+        .vpn_connect(name.as_deref().unwrap_or(DEFAULT_TUNNEL), config).await
+        .map_err(|e| e.to_string())
 }
 
-/// Disconnect from the active VPN tunnel.
+/// Disconnect a VPN tunnel.
 #[tauri::command]
-async fn vpn_disconnect() -> Result<(), String> {
+async fn vpn_disconnect(name: Option<String>) -> Result<(), String> {
     let mut client = client::DaemonClient
         ::connect().await
         .map_err(|e| format!("cannot connect to daemon: {e}"))?;
 
-    client.vpn_disconnect().await.map_err(|e| e.to_string())
+    client
+        .vpn_disconnect(name.as_deref().unwrap_or(DEFAULT_TUNNEL)).await
+        .map_err(|e| e.to_string())
 }
 
-/// Query the current tunnel status.
+/// Query the current status of a tunnel.
 #[tauri::command]
-async fn vpn_status() -> Result<TunnelStatus, String> {
+async fn vpn_status(name: Option<String>) -> Result<TunnelStatus, String> {
     let mut client = client::DaemonClient
         ::connect().await
         .map_err(|e| format!("cannot connect to daemon: {e}"))?;
 
-    client.vpn_status().await.map_err(|e| e.to_string())
+    client.vpn_status(name.as_deref().unwrap_or(DEFAULT_TUNNEL)).await.map_err(|e| e.to_string())
 }
 
-/// Query transfer statistics for the active tunnel.
+/// Query transfer statistics for a tunnel.
 #[tauri::command]
-async fn vpn_stats() -> Result<TunnelStats, String> {
+async fn vpn_stats(name: Option<String>) -> Result<TunnelStats, String> {
     let mut client = client::DaemonClient
         ::connect().await
         .map_err(|e| format!("cannot connect to daemon: {e}"))?;
 
-    client.vpn_stats().await.map_err(|e| e.to_string())
+    client.vpn_stats(name.as_deref().unwrap_or(DEFAULT_TUNNEL)).await.map_err(|e| e.to_string())
+}
+
+/// List every tunnel with its status and statistics.
+#[tauri::command]
+async fn vpn_tunnels() -> Result<Vec<TunnelInfo>, String> {
+    let mut client = client::DaemonClient
+        ::connect().await
+        .map_err(|e| format!("cannot connect to daemon: {e}"))?;
+
+    client.vpn_tunnels().await.map_err(|e| e.to_string())
+}
+
+/// Turn the daemon's kill switch on or off.
+#[tauri::command]
+async fn vpn_set_kill_switch(enabled: bool) -> Result<(), String> {
+    let mut client = client::DaemonClient
+        ::connect().await
+        .map_err(|e| format!("cannot connect to daemon: {e}"))?;
+
+    client.vpn_set_kill_switch(enabled).await.map_err(|e| e.to_string())
+}
+
+/// Whether the daemon's kill switch is on.
+#[tauri::command]
+async fn vpn_get_kill_switch() -> Result<bool, String> {
+    let mut client = client::DaemonClient
+        ::connect().await
+        .map_err(|e| format!("cannot connect to daemon: {e}"))?;
+
+    client.vpn_kill_switch().await.map_err(|e| e.to_string())
 }
 
 /// Return the built-in list of available VPN servers.
@@ -54,6 +95,36 @@ async fn list_servers() -> Result<Vec<ServerInfo>, String> {
     Ok(builtin_servers())
 }
 
+// ── Daemon events ─────────────────────────────────────────────────────────────
+
+/// Frontend event carrying each `shared::VpnEvent` from the daemon.
+pub const VPN_EVENT: &str = "vpn-event";
+
+/// Wait before subscribing again after losing the daemon.
+const RESUBSCRIBE_DELAY: Duration = Duration::from_secs(2);
+
+/// Forward the daemon's events to the frontend for as long as the app runs,
+/// subscribing again whenever the daemon restarts.
+async fn forward_events(app: tauri::AppHandle) {
+    loop {
+        if let Err(e) = forward_subscription(&app).await {
+            eprintln!("daemon event subscription failed: {e}");
+        }
+        tokio::time::sleep(RESUBSCRIBE_DELAY).await;
+    }
+}
+
+/// Forward one subscription's events until the daemon closes it.
+async fn forward_subscription(app: &tauri::AppHandle) -> anyhow::Result<()> {
+    let client = client::DaemonClient::connect().await?;
+    let mut events = std::pin::pin!(client.subscribe().await?);
+
+    while let Some(event) = events.next().await {
+        app.emit(VPN_EVENT, event?)?;
+    }
+    Ok(())
+}
+
 // ── App setup ─────────────────────────────────────────────────────────────────
 
 /// Application settings persisted in the Tauri store.
@@ -141,6 +212,10 @@ pub fn run() {
     tauri::Builder
         ::default()
         .plugin(tauri_plugin_shell::init())
+        .setup(|app| {
+            tauri::async_runtime::spawn(forward_events(app.handle().clone()));
+            Ok(())
+        })
         .invoke_handler(
             tauri::generate_handler![
                
@@ -148,6 +223,9 @@ pub fn run() {
                 vpn_disconnect,
                 vpn_status,
                 vpn_stats,
+                vpn_tunnels,
+                vpn_set_kill_switch,
+                vpn_get_kill_switch,
                 list_servers
             ]
   
@@ -3,6 +3,7 @@ import MainScreen from "./screens/MainScreen";
 import ServerListScreen from "./screens/ServerListScreen";
 import SettingsScreen from "./screens/SettingsScreen";
 import { AppSettings, DEFAULT_SETTINGS, ServerInfo } from "./types";
+import { vpnGetKillSwitch, vpnSetKillSwitch } from "./tauri-commands";
 
 type Screen = "main" | "servers" | "settings";
 
@@ -30,10 +31,26 @@ export default function App() {
     saveSettings(settings);
   }, [settings]);
 
-  const handleSettingsChange = useCallback((updated: AppSettings) => {
-    setSettings(updated);
+  // The daemon keeps the kill switch, which the CLI may have changed, so show
+  // its setting rather than ours.
+  useEffect(() => {
+    vpnGetKillSwitch()
+      .then((enabled) => setSettings((s) => ({ ...s, kill_switch: enabled })))
+      .catch((e) => console.error("failed to read kill switch:", e));
   }, []);
 
+  const handleSettingsChange = useCallback(
+    (updated: AppSettings) => {
+      if (updated.kill_switch !== settings.kill_switch) {
+        vpnSetKillSwitch(updated.kill_switch).catch((e) =>
+          console.error("failed to set kill switch:", e)
+        );
+      }
+      setSettings(updated);
+    },
+    [settings.kill_switch]
+  );
+
   const handleServerSelect = useCallback(
     (server: ServerInfo) => {
       setSelectedServer(server);
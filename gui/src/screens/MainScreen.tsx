@@ -1,7 +1,16 @@
 import { useRef, useEffect, useState } from "react";
 import Globe from "react-globe.gl";
 
-import { ServerInfo } from "../types";
+import {
+  DEFAULT_TUNNEL,
+  ServerInfo,
+  TunnelStats,
+  TunnelStatus,
+  formatRate,
+  isConnected,
+  statusLabel,
+} from "../types";
+import { onVpnEvent, vpnStatus } from "../tauri-commands";
 import { invoke } from "@tauri-apps/api/core";
 
 interface Props {
@@ -12,9 +21,12 @@ interface Props {
 
 export default function MainScreen({ selectedServer }: Props) {
   const globeRef = useRef<any>();
-  const [connected, setConnected] = useState(false);
+  const [status, setStatus] = useState<TunnelStatus>("Disconnected");
+  const connected = isConnected(status);
+  // Anything but Disconnected has a tunnel (or a failed one) to tear down.
+  const active = status !== "Disconnected";
 
-  const [ping, setPing] = useState<number | null>(null);
+  const [stats, setStats] = useState<TunnelStats | null>(null);
   const [ip, setIp] = useState("0.0.0.0");
 
   useEffect(() => {
@@ -24,16 +36,31 @@ export default function MainScreen({ selectedServer }: Props) {
     }
   }, []);
 
-  // Fake ping simulation
+  // The daemon owns the connection state and pushes every change, along with
+  // throughput and handshake round trips for spotting a slow tunnel.
+  useEffect(() => {
+    vpnStatus()
+      .then(setStatus)
+      .catch((e) => console.error("failed to get status:", e));
+    const unlisten = onVpnEvent((event) => {
+      if ("StatusChanged" in event && event.StatusChanged.name === DEFAULT_TUNNEL) {
+        setStatus(event.StatusChanged.status);
+      } else if ("Stats" in event && event.Stats.name === DEFAULT_TUNNEL) {
+        setStats(event.Stats.stats);
+      } else if ("Error" in event && event.Error.name === DEFAULT_TUNNEL) {
+        console.warn("tunnel error:", event.Error.message);
+      }
+    });
+    return () => {
+      unlisten.then((stop) => stop());
+    };
+  }, []);
+
   useEffect(() => {
     if (connected) {
       setIp("185.199.108.153");
-      const interval = setInterval(() => {
-        setPing(Math.floor(Math.random() * 40) + 20);
-      }, 2000);
-      return () => clearInterval(interval);
     } else {
-      setPing(null);
+      setStats(null);
       setIp("0.0.0.0");
     }
   }, [connected]);
@@ -41,21 +68,20 @@ export default function MainScreen({ selectedServer }: Props) {
     if (!selectedServer) return;
 
     try {
-      if (!connected) {
+      if (!active) {
+        setStatus("Connecting");
         await invoke("vpn_connect", {
           config: {
             server_id: selectedServer.id
           }
         });
-
-        setConnected(true);
       } else {
         await invoke("vpn_disconnect");
-        setConnected(false);
       }
     } catch (err) {
       console.error("VPN error:", err);
     }
+    setStatus(await vpnStatus().catch(() => status));
   };
 
   return (
@@ -66,8 +92,8 @@ export default function MainScreen({ selectedServer }: Props) {
 
       {/* Status */}
       <div className="absolute top-6 right-8 z-20 text-sm font-medium">
-        <span className={connected ? "text-green-400" : "text-red-400"}>
-          {connected ? "Connected" : "Disconnected"}
+        <span className={connected ? "text-green-400" : active ? "text-yellow-400" : "text-red-400"}>
+          {statusLabel(status)}
         </span>
       </div>
 
@@ -99,9 +125,23 @@ export default function MainScreen({ selectedServer }: Props) {
           <div>
             Ping:{" "}
             <span className={connected ? "text-green-400" : "text-gray-500"}>
-              {ping ? `${ping} ms` : "--"}
+              {stats?.handshake_rtt_ms != null ? `${stats.handshake_rtt_ms} ms` : "--"}
             </span>
           </div>
+          <div>
+            ↑ <span className="text-white">{stats ? formatRate(stats.bytes_sent_per_sec) : "--"}</span>
+          </div>
+          <div>
+            ↓ <span className="text-white">{stats ? formatRate(stats.bytes_received_per_sec) : "--"}</span>
+          </div>
+          {stats && stats.decrypt_failures + stats.replay_drops + stats.mtu_drops > 0 && (
+            <div title={`${stats.decrypt_failures} failed decryption, ${stats.replay_drops} replayed, ${stats.mtu_drops} over MTU`}>
+              Dropped:{" "}
+              <span className="text-yellow-400">
+                {stats.decrypt_failures + stats.replay_drops + stats.mtu_drops}
+              </span>
+            </div>
+          )}
         </div>
 
         {/* Server */}
@@ -6,24 +6,44 @@
  */
 
 import { invoke } from "@tauri-apps/api/core";
-import type { ServerInfo, TunnelStats, TunnelStatus, VpnConfig } from "./types";
+import { listen, type UnlistenFn } from "@tauri-apps/api/event";
+import type { ServerInfo, TunnelInfo, TunnelStats, TunnelStatus, VpnConfig, VpnEvent } from "./types";
 
-export async function vpnConnect(config: VpnConfig): Promise<void> {
-  await invoke<void>("vpn_connect", { config });
+// `name` picks the tunnel; the daemon's default tunnel when omitted.
+
+export async function vpnConnect(config: VpnConfig, name?: string): Promise<void> {
+  await invoke<void>("vpn_connect", { config, name });
+}
+
+export async function vpnDisconnect(name?: string): Promise<void> {
+  await invoke<void>("vpn_disconnect", { name });
+}
+
+export async function vpnStatus(name?: string): Promise<TunnelStatus> {
+  return invoke<TunnelStatus>("vpn_status", { name });
 }
 
-export async function vpnDisconnect(): Promise<void> {
-  await invoke<void>("vpn_disconnect");
+export async function vpnStats(name?: string): Promise<TunnelStats> {
+  return invoke<TunnelStats>("vpn_stats", { name });
 }
 
-export async function vpnStatus(): Promise<TunnelStatus> {
-  return invoke<TunnelStatus>("vpn_status");
+export async function vpnTunnels(): Promise<TunnelInfo[]> {
+  return invoke<TunnelInfo[]>("vpn_tunnels");
 }
 
-export async function vpnStats(): Promise<TunnelStats> {
-  return invoke<TunnelStats>("vpn_stats");
+export async function vpnSetKillSwitch(enabled: boolean): Promise<void> {
+  await invoke<void>("vpn_set_kill_switch", { enabled });
+}
+
+export async function vpnGetKillSwitch(): Promise<boolean> {
+  return invoke<boolean>("vpn_get_kill_switch");
 }
 
 export async function listServers(): Promise<ServerInfo[]> {
   return invoke<ServerInfo[]>("list_servers");
 }
+
+/** Call `handler` with every event the daemon pushes; resolves to a function that stops listening. */
+export async function onVpnEvent(handler: (event: VpnEvent) => void): Promise<UnlistenFn> {
+  return listen<VpnEvent>("vpn-event", (event) => handler(event.payload));
+}
@@ -1,15 +1,47 @@
+/** A std::time::SystemTime as serde serializes it. */
+export interface SystemTime {
+  secs_since_epoch: number;
+  nanos_since_epoch: number;
+}
+
 /** Mirror of shared::TunnelStatus from Rust. */
 export type TunnelStatus =
   | "Disconnected"
   | "Connecting"
-  | { Connected: { since: string; server: string } }
+  | { Connected: { since: SystemTime; server: string } }
+  | { Reconnecting: { server: string; attempt: number; next_attempt: SystemTime | null } }
   | { Error: string };
 
 /** Mirror of shared::TunnelStats from Rust. */
 export interface TunnelStats {
   bytes_sent: number;
   bytes_received: number;
-  last_handshake: string | null;
+  packets_sent: number;
+  packets_received: number;
+  last_handshake: SystemTime | null;
+  handshake_rtt_ms: number | null;
+  decrypt_failures: number;
+  replay_drops: number;
+  mtu_drops: number;
+  bytes_sent_per_sec: number;
+  bytes_received_per_sec: number;
+}
+
+/** Mirror of shared::DEFAULT_TUNNEL: the tunnel used when none is named. */
+export const DEFAULT_TUNNEL = "default";
+
+/** Mirror of shared::VpnEvent from Rust, as forwarded on "vpn-event". */
+export type VpnEvent =
+  | { StatusChanged: { name: string; status: TunnelStatus } }
+  | { HandshakeCompleted: { name: string; rtt_ms: number } }
+  | { Stats: { name: string; stats: TunnelStats } }
+  | { Error: { name: string; message: string } };
+
+/** Mirror of shared::TunnelInfo from Rust. */
+export interface TunnelInfo {
+  name: string;
+  status: TunnelStatus;
+  stats: TunnelStats;
 }
 
 /** Mirror of shared::VpnConfig from Rust. */
@@ -17,9 +49,16 @@ export interface VpnConfig {
   server_addr: string;
   server_public_key: string;
   client_private_key: string;
-  client_ip: string;
+  client_ips: string[];
+  prefix_len?: number | null;
+  prefix_len_v6?: number | null;
+  mtu?: number | null;
+  interface_name?: string | null;
   dns_servers: string[];
   allowed_ips: string[];
+  preshared_key?: string | null;
+  reconnect_max_delay_secs?: number | null;
+  persistent_keepalive_secs?: number | null;
 }
 
 /** Mirror of shared::ServerInfo from Rust. */
@@ -63,6 +102,11 @@ export function formatBytes(bytes: number): string {
   return `${(bytes / 1024 / 1024 / 1024).toFixed(2)} GB`;
 }
 
+/** Format a throughput in bytes per second, e.g. "1.2 MB/s". */
+export function formatRate(bytesPerSec: number): string {
+  return `${formatBytes(bytesPerSec)}/s`;
+}
+
 /** Format seconds as hh:mm:ss. */
 export function formatDuration(seconds: number): string {
   const h = Math.floor(seconds / 3600);
@@ -75,3 +119,11 @@ export function formatDuration(seconds: number): string {
 export function isConnected(status: TunnelStatus): boolean {
   return typeof status === "object" && "Connected" in status;
 }
+
+/** Short label for a status, as shown in the header. */
+export function statusLabel(status: TunnelStatus): string {
+  if (typeof status === "string") return status;
+  if ("Connected" in status) return "Connected";
+  if ("Reconnecting" in status) return `Reconnecting (attempt ${status.Reconnecting.attempt})`;
+  return `Error: ${status.Error}`;
+}
@@ -0,0 +1,43 @@
+//! Minimal IP header inspection used to route packets between clients.
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+/// Fixed IPv6 header length; addresses sit at offsets 8 and 24.
+const IPV6_HEADER_LEN: usize = 40;
+
+/// Source address of an IP packet, if the header is well formed.
+pub fn source(packet: &[u8]) -> Option<IpAddr> {
+    match version(packet)? {
+        4 if packet.len() >= 20 => Some(IpAddr::V4(ipv4_at(packet, 12))),
+        6 if packet.len() >= IPV6_HEADER_LEN => Some(IpAddr::V6(ipv6_at(packet, 8))),
+        _ => None,
+    }
+}
+
+/// Destination address of an IP packet, if the header is well formed.
+pub fn destination(packet: &[u8]) -> Option<IpAddr> {
+    match version(packet)? {
+        4 if packet.len() >= 20 => Some(IpAddr::V4(ipv4_at(packet, 16))),
+        6 if packet.len() >= IPV6_HEADER_LEN => Some(IpAddr::V6(ipv6_at(packet, 24))),
+        _ => None,
+    }
+}
+
+fn version(packet: &[u8]) -> Option<u8> {
+    packet.first().map(|b| b >> 4)
+}
+
+fn ipv4_at(packet: &[u8], offset: usize) -> Ipv4Addr {
+    Ipv4Addr::new(
+        packet[offset],
+        packet[offset + 1],
+        packet[offset + 2],
+        packet[offset + 3],
+    )
+}
+
+fn ipv6_at(packet: &[u8], offset: usize) -> Ipv6Addr {
+    let mut octets = [0u8; 16];
+    octets.copy_from_slice(&packet[offset..offset + 16]);
+    Ipv6Addr::from(octets)
+}
@@ -1,134 +1,338 @@
-use tokio::net::{UdpSocket, TcpStream};
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-
-use chacha20poly1305::{
-    ChaCha20Poly1305,
-    Key,
-    Nonce,
-    aead::{Aead, KeyInit}
-};
-
-const KEY_BYTES: [u8; 32] = [1; 32];
+//! NySVPN relay server.
+//!
+//! Terminates client tunnels: decrypted IP packets are injected into a local
+//! TUN interface and NATed out to the internet, and packets coming back
+//! through the TUN are encrypted and sent to the client that owns the
+//! destination tunnel address.
+
+mod ip;
+mod nat;
+mod peers;
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::process::Command;
+use std::time::{Duration, Instant};
+
+use clap::Parser;
+use futures::{SinkExt, StreamExt};
+use tokio::net::UdpSocket;
+use tokio::signal::unix::{SignalKind, signal};
+use tun::{Device as _, TunPacket};
+
+use nysvpn_core::crypto::Session;
+use nysvpn_core::handshake;
+use nysvpn_core::keys::{KeyError, PresharedKey, PrivateKey, PublicKey};
+use nysvpn_core::wire::Message;
+
+use peers::{PeerRegistry, Received};
+
+#[derive(Parser)]
+#[command(name = "nysvpb-server", about = "NySVPN relay server")]
+struct Args {
+    /// UDP address to listen on
+    #[arg(long, default_value = "0.0.0.0:51820")]
+    listen: SocketAddr,
+
+    /// Server private key (base64)
+    #[arg(long)]
+    private_key: String,
+
+    /// Server address on the tunnel network
+    #[arg(long, default_value = "10.0.0.1")]
+    tun_address: Ipv4Addr,
+
+    /// Prefix length of the tunnel network
+    #[arg(long, default_value_t = 24, value_parser = clap::value_parser!(u8).range(..=32))]
+    tun_prefix: u8,
+
+    /// Server IPv6 address on the tunnel network; enables IPv6 forwarding and NAT66
+    #[arg(long)]
+    tun_address6: Option<Ipv6Addr>,
+
+    /// Prefix length of the IPv6 tunnel network
+    #[arg(long, default_value_t = 64, value_parser = clap::value_parser!(u8).range(..=128))]
+    tun_prefix6: u8,
+
+    /// MTU of the server TUN interface
+    #[arg(long, default_value_t = 1420)]
+    mtu: i32,
+
+    /// Only NAT traffic leaving through this interface (e.g. eth0)
+    #[arg(long)]
+    egress: Option<String>,
+
+    /// Authorised client as <public-key>=<tunnel-ip>[,<tunnel-ip>...]; repeat
+    /// for each client
+    #[arg(long = "peer", value_parser = parse_peer)]
+    peers: Vec<(PublicKey, Vec<IpAddr>)>,
+
+    /// Pre-shared key for a client as <public-key>=<psk>; the client must be
+    /// configured with the same key
+    #[arg(long = "peer-psk", value_parser = parse_peer_psk)]
+    peer_psks: Vec<(PublicKey, PresharedKey)>,
+}
+
+/// Length of a base64-encoded 32-byte key, including its `=` padding.
+const KEY_B64_LEN: usize = 44;
+
+/// Split `<public-key>=<value>`.  Base64 keys end in `=` themselves, so the
+/// separator is the `=` right after the key rather than the first one.
+fn split_key_arg<'a>(s: &'a str, expected: &str) -> Result<(PublicKey, &'a str), String> {
+    let (key, value) = match (s.get(..KEY_B64_LEN), s.get(KEY_B64_LEN..)) {
+        (Some(key), Some(rest)) => (key, rest.strip_prefix('=')),
+        _ => (s, None),
+    };
+    let value = value.ok_or_else(|| format!("expected {expected}"))?;
+    let key = key.parse().map_err(|e: KeyError| e.to_string())?;
+    Ok((key, value))
+}
+
+/// Parse a `--peer <public-key>=<tunnel-ip>[,<tunnel-ip>...]` argument.
+fn parse_peer(s: &str) -> Result<(PublicKey, Vec<IpAddr>), String> {
+    let (key, ips) = split_key_arg(s, "<public-key>=<tunnel-ip>")?;
+    let ips = ips
+        .split(',')
+        .map(|ip| {
+            ip.trim()
+                .parse()
+                .map_err(|e| format!("invalid tunnel IP {ip:?}: {e}"))
+        })
+        .collect::<Result<Vec<IpAddr>, String>>()?;
+    Ok((key, ips))
+}
+
+/// Parse a `--peer-psk <public-key>=<psk>` argument.
+fn parse_peer_psk(s: &str) -> Result<(PublicKey, PresharedKey), String> {
+    let (key, psk) = split_key_arg(s, "<public-key>=<psk>")?;
+    let psk = psk
+        .parse()
+        .map_err(|e: KeyError| format!("invalid PSK: {e}"))?;
+    Ok((key, psk))
+}
 
 #[tokio::main]
 async fn main() {
-
-    println!("NySVPN full forward server started");
+    let args = Args::parse();
+
+    let private_key: PrivateKey = args.private_key.parse().expect("invalid --private-key");
+
+    let mask = u32::MAX
+        .checked_shl(32 - u32::from(args.tun_prefix))
+        .unwrap_or(0);
+    let network = Ipv4Addr::from(u32::from(args.tun_address) & mask);
+
+    let mut config = tun::Configuration::default();
+    config
+        .address(args.tun_address)
+        .netmask(Ipv4Addr::from(mask))
+        .mtu(args.mtu)
+        .up();
+
+    let device = tun::create_as_async(&config).expect("TUN creation failed");
+    let tun_name = device.get_ref().name().expect("TUN has no name");
+
+    let network6 = args.tun_address6.map(|address| {
+        add_ipv6_address(&tun_name, address, args.tun_prefix6).expect("IPv6 address setup failed");
+        let mask = u128::MAX
+            .checked_shl(128 - u32::from(args.tun_prefix6))
+            .unwrap_or(0);
+        format!(
+            "{}/{}",
+            Ipv6Addr::from(u128::from(address) & mask),
+            args.tun_prefix6
+        )
+    });
+
+    let _nat = nat::Nat::enable(
+        &tun_name,
+        &format!("{network}/{}", args.tun_prefix),
+        network6.as_deref(),
+        args.egress.as_deref(),
+    )
+    .expect("NAT setup failed");
 
     // VPN socket
-    let vpn_socket: UdpSocket =
-        UdpSocket::bind("0.0.0.0:51820")
-        .await
-        .expect("bind failed");
-
-    // cipher
-    let cipher =
-        ChaCha20Poly1305::new(
-            Key::from_slice(&KEY_BYTES)
-        );
-
-    let mut buf: [u8; 2000] = [0; 2000];
-
-    loop {
-
-        // receive VPN packet
-        let (len, client_addr) =
-            vpn_socket.recv_from(&mut buf)
-            .await
-            .expect("recv failed");
-
-        if len < 12 {
-            continue;
-        }
+    let vpn_socket = UdpSocket::bind(args.listen).await.expect("bind failed");
 
-        // split nonce and data
-        let mut nonce_array: [u8; 12] = [0; 12];
-        nonce_array.copy_from_slice(&buf[0..12]);
+    println!(
+        "NySVPN relay listening on {} – {tun_name} {network}/{}{}",
+        args.listen,
+        args.tun_prefix,
+        network6.map(|n| format!(", {n}")).unwrap_or_default()
+    );
 
-        let encrypted =
-            &buf[12..len];
+    let (mut tun_sink, mut tun_stream) = device.into_framed().split();
 
-        // decrypt packet
-        let decrypted =
-            match cipher.decrypt(
-                Nonce::from_slice(&nonce_array),
-                encrypted
-            ) {
-
-                Ok(data) => data,
-
-                Err(_) => {
-                    println!("decrypt failed");
-                    continue;
-                }
-
-            };
-
-        println!(
-            "VPN packet received: {} bytes",
-            decrypted.len()
+    let mut peers = PeerRegistry::default();
+    for (public_key, tunnel_ips) in &args.peers {
+        peers.add(*public_key.as_bytes(), tunnel_ips.clone());
+    }
+    for (public_key, psk) in &args.peer_psks {
+        assert!(
+            peers.set_preshared_key(public_key.as_bytes(), psk.clone()),
+            "--peer-psk given for a key that is not a --peer"
         );
+    }
+    println!("{} peer(s) configured", args.peers.len());
 
-        // connect to internet (example.com test)
-        let mut internet =
-            match TcpStream::connect("example.com:80")
-            .await {
-
-                Ok(s) => s,
+    let mut sigint = signal(SignalKind::interrupt()).expect("signal handler");
+    let mut sigterm = signal(SignalKind::terminate()).expect("signal handler");
+    let mut expiry = tokio::time::interval(Duration::from_secs(1));
+    let mut buf = [0u8; 2048];
 
-                Err(e) => {
-                    println!("internet connect failed: {}", e);
+    loop {
+        tokio::select! {
+            _ = sigint.recv() => break,
+            _ = sigterm.recv() => break,
+
+            _ = expiry.tick() => peers.expire(),
+
+            received = vpn_socket.recv_from(&mut buf) => {
+                let (len, client_addr) = received.expect("recv failed");
+
+                match Message::parse(&buf[..len]) {
+                    Some(Message::HandshakeInit { sender, payload }) => {
+                        let accepted = match handshake::respond(
+                            private_key.as_bytes(),
+                            sender,
+                            payload,
+                            |key| peers.preshared_key(key),
+                            |index| peers.index_in_use(index),
+                        ) {
+                            Ok(a) => a,
+                            Err(e) => {
+                                println!("handshake from {client_addr} failed: {e}");
+                                continue;
+                            }
+                        };
+
+                        let public_key = accepted.remote_public;
+                        let local_index = accepted.keys.local_index;
+
+                        let session = Session::new(accepted.keys, Instant::now());
+                        if let Err(e) = peers.establish(&public_key, session, accepted.timestamp) {
+                            println!("handshake from {client_addr} rejected: {e}");
+                            continue;
+                        }
+
+                        let response = Message::HandshakeResponse {
+                            sender: local_index,
+                            receiver: sender,
+                            payload: &accepted.response,
+                        };
+                        let _ = vpn_socket.send_to(&response.encode(), client_addr).await;
+
+                        println!("handshake completed with {client_addr}");
+                    }
+
+                    Some(Message::Transport { receiver, counter, ciphertext }) => {
+                        let Received { peer, packet, roamed_from } = match peers.receive(receiver, counter, ciphertext, client_addr, Instant::now()) {
+                            Some(Ok(received)) => received,
+                            Some(Err(e)) => {
+                                println!("dropped packet from {client_addr}: {e}");
+                                continue;
+                            }
+                            None => continue,
+                        };
+                        if let Some(previous) = roamed_from {
+                            println!("peer {:?} roamed from {previous} to {client_addr}", peer.tunnel_ips);
+                        }
+
+                        // Empty messages only confirm the session keys or keep a
+                        // NAT mapping open.
+                        if packet.is_empty() {
+                            continue;
+                        }
+
+                        // A peer may only send from the tunnel addresses it was assigned.
+                        if !ip::source(&packet).is_some_and(|src| peer.tunnel_ips.contains(&src)) {
+                            continue;
+                        }
+
+                        peer.rx_packets += 1;
+                        peer.rx_bytes += packet.len() as u64;
+
+                        if let Err(e) = tun_sink.send(TunPacket::new(packet)).await {
+                            println!("TUN write failed: {e}");
+                        }
+                    }
+
+                    _ => {}
+                }
+            }
+
+            packet = tun_stream.next() => {
+                let Some(Ok(packet)) = packet else {
+                    println!("TUN read failed");
+                    break;
+                };
+                let packet = packet.get_bytes();
+
+                let Some(peer) = ip::destination(packet)
+                    .and_then(|dst| peers.by_tunnel_ip_mut(&dst))
+                else {
+                    continue;
+                };
+                let now = Instant::now();
+                let (Some(session), Some(endpoint)) = (peer.keyring.current_mut(now), peer.endpoint)
+                else {
                     continue;
+                };
+
+                let datagram = match session.seal(packet, now) {
+                    Ok(datagram) => datagram,
+                    Err(e) => {
+                        println!("dropped packet for {:?}: {e}", peer.tunnel_ips);
+                        continue;
+                    }
+                };
+
+                if vpn_socket.send_to(&datagram, endpoint).await.is_ok() {
+                    peer.tx_packets += 1;
+                    peer.tx_bytes += packet.len() as u64;
                 }
-
-            };
-
-        // send HTTP request
-        let request =
-            b"GET / HTTP/1.1\r\nHost: example.com\r\nConnection: close\r\n\r\n";
-
-        if internet.write_all(request).await.is_err() {
-            continue;
+            }
         }
+    }
 
-        // read response
-        let mut response =
-            vec![0u8; 4096];
-
-        let size =
-            match internet.read(&mut response).await {
-
-                Ok(s) => s,
-
-                Err(_) => continue,
-
-            };
+    println!("NySVPN relay shutting down");
 
+    for peer in peers.iter() {
         println!(
-            "Internet response: {} bytes",
-            size
+            "peer {:?}: endpoint {}, last seen {}, rx {} packets / {} bytes, tx {} packets / {} bytes",
+            peer.tunnel_ips,
+            peer.endpoint
+                .map(|e| e.to_string())
+                .unwrap_or_else(|| "none".to_string()),
+            peer.last_seen
+                .map(|t| format!("{}s ago", t.elapsed().as_secs()))
+                .unwrap_or_else(|| "never".to_string()),
+            peer.rx_packets,
+            peer.rx_bytes,
+            peer.tx_packets,
+            peer.tx_bytes,
         );
-
-        // encrypt response
-        let encrypted_response =
-            cipher.encrypt(
-                Nonce::from_slice(&nonce_array),
-                &response[..size]
-            ).unwrap();
-
-        // send back to client
-        let mut packet =
-            Vec::new();
-
-        packet.extend_from_slice(&nonce_array);
-
-        packet.extend_from_slice(&encrypted_response);
-
-        vpn_socket.send_to(
-            &packet,
-            client_addr
-        ).await.unwrap();
-
     }
-
-}
\ No newline at end of file
+}
+
+/// Assign an IPv6 address to the TUN; the tun crate only handles IPv4.
+fn add_ipv6_address(tun: &str, address: Ipv6Addr, prefix: u8) -> std::io::Result<()> {
+    let status = Command::new("ip")
+        .args([
+            "-6",
+            "addr",
+            "add",
+            &format!("{address}/{prefix}"),
+            "dev",
+            tun,
+        ])
+        .status()?;
+
+    if status.success() {
+        Ok(())
+    } else {
+        Err(std::io::Error::other(format!(
+            "ip -6 addr add exited with {status}"
+        )))
+    }
+}
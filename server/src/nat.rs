@@ -0,0 +1,136 @@
+//! Kernel forwarding and NAT setup for the relay.
+//!
+//! Client traffic leaves the TUN interface and is masqueraded behind the
+//! server's own address, so replies from the internet come back to us and
+//! can be routed into the right tunnel.  IPv6 traffic gets the same
+//! treatment (NAT66) through `ip6tables` when the tunnel has an IPv6 subnet.
+//! Every rule added here is removed again when [`Nat`] is dropped.
+
+use std::io;
+use std::process::Command;
+
+const IP_FORWARD: &str = "/proc/sys/net/ipv4/ip_forward";
+const IPV6_FORWARD: &str = "/proc/sys/net/ipv6/conf/all/forwarding";
+
+/// Active forwarding/NAT configuration; torn down on drop.
+pub struct Nat {
+    /// Rule specs we appended, minus the `-A` action, with the program
+    /// (`iptables` or `ip6tables`) that owns them.
+    rules: Vec<(&'static str, Vec<String>)>,
+    /// Forwarding sysctls we changed and their previous values, restored on
+    /// drop.
+    previous_forwarding: Vec<(&'static str, String)>,
+}
+
+impl Nat {
+    /// Enable IPv4 forwarding and masquerade traffic from `subnet` arriving on
+    /// the `tun` interface. With `subnet6`, IPv6 traffic from that subnet is
+    /// forwarded and masqueraded too. When `egress` is given, NAT is limited to
+    /// traffic leaving through that interface.
+    pub fn enable(
+        tun: &str,
+        subnet: &str,
+        subnet6: Option<&str>,
+        egress: Option<&str>,
+    ) -> io::Result<Self> {
+        let mut nat = Self {
+            rules: Vec::new(),
+            previous_forwarding: Vec::new(),
+        };
+
+        nat.forward(IP_FORWARD)?;
+        nat.masquerade("iptables", tun, subnet, egress)?;
+
+        if let Some(subnet6) = subnet6 {
+            nat.forward(IPV6_FORWARD)?;
+            nat.masquerade("ip6tables", tun, subnet6, egress)?;
+        }
+
+        Ok(nat)
+    }
+
+    /// Turn on the forwarding sysctl at `path`, remembering its old value.
+    fn forward(&mut self, path: &'static str) -> io::Result<()> {
+        let previous = std::fs::read_to_string(path)?;
+        std::fs::write(path, "1")?;
+        self.previous_forwarding.push((path, previous));
+        Ok(())
+    }
+
+    /// Add the MASQUERADE and FORWARD rules for one address family.
+    fn masquerade(
+        &mut self,
+        program: &'static str,
+        tun: &str,
+        subnet: &str,
+        egress: Option<&str>,
+    ) -> io::Result<()> {
+        let mut masquerade = vec!["-t", "nat", "POSTROUTING", "-s", subnet];
+        if let Some(egress) = egress {
+            masquerade.extend(["-o", egress]);
+        }
+        masquerade.extend(["-j", "MASQUERADE"]);
+
+        self.append(program, &masquerade)?;
+        self.append(program, &["FORWARD", "-i", tun, "-j", "ACCEPT"])?;
+        self.append(
+            program,
+            &[
+                "FORWARD",
+                "-o",
+                tun,
+                "-m",
+                "conntrack",
+                "--ctstate",
+                "RELATED,ESTABLISHED",
+                "-j",
+                "ACCEPT",
+            ],
+        )
+    }
+
+    /// Append a rule with `program` and remember it for removal.
+    fn append(&mut self, program: &'static str, spec: &[&str]) -> io::Result<()> {
+        let spec: Vec<String> = spec.iter().map(|s| s.to_string()).collect();
+        iptables(program, "-A", &spec)?;
+        self.rules.push((program, spec));
+        Ok(())
+    }
+}
+
+impl Drop for Nat {
+    fn drop(&mut self) {
+        for (program, spec) in self.rules.drain(..).rev() {
+            if let Err(e) = iptables(program, "-D", &spec) {
+                println!("failed to remove {program} rule {spec:?}: {e}");
+            }
+        }
+
+        for (path, previous) in self.previous_forwarding.drain(..).rev() {
+            let _ = std::fs::write(path, previous.trim());
+        }
+    }
+}
+
+/// Run `program` (`iptables` or `ip6tables`) with `action` inserted after an
+/// optional `-t <table>` prefix.
+fn iptables(program: &str, action: &str, spec: &[String]) -> io::Result<()> {
+    let (table, chain) = match spec {
+        [flag, table, rest @ ..] if flag == "-t" => (vec![flag.as_str(), table.as_str()], rest),
+        rest => (Vec::new(), rest),
+    };
+
+    let status = Command::new(program)
+        .args(table)
+        .arg(action)
+        .args(chain)
+        .status()?;
+
+    if status.success() {
+        Ok(())
+    } else {
+        Err(io::Error::other(format!(
+            "{program} {action} exited with {status}"
+        )))
+    }
+}
@@ -0,0 +1,394 @@
+//! Registry of authorised clients and their live sessions.
+//!
+//! Peers are configured up front with their static public key and the tunnel
+//! addresses (IPv4 and/or IPv6) they own.  A handshake adds a session to the
+//! peer's keyring; packets from the internet are routed to whichever peer owns
+//! the destination tunnel address, and sent to the endpoint that peer last
+//! sent an authenticated packet from.
+//!
+//! Only packets that decrypt under one of the peer's sessions, and pass its
+//! replay window, may move the endpoint; a spoofed or replayed datagram never
+//! redirects a peer's traffic.  Handshake initiations do not move it either,
+//! and are rejected unless their timestamp is newer than the peer's last one.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::time::Instant;
+
+use nysvpn_core::crypto::{CryptoError, Keyring, Session};
+use nysvpn_core::handshake::{self, Timestamp};
+use nysvpn_core::keys::PresharedKey;
+
+/// An authorised client.
+pub struct Peer {
+    /// Tunnel addresses assigned to this peer; it may only send from these.
+    pub tunnel_ips: Vec<IpAddr>,
+    /// Pre-shared key mixed into this peer's handshakes, if one is configured.
+    pub preshared_key: Option<PresharedKey>,
+    /// Current, pending and previous sessions across rekeys.
+    pub keyring: Keyring,
+    /// UDP endpoint the peer last sent an authenticated packet from.
+    pub endpoint: Option<SocketAddr>,
+    /// Timestamp of the newest handshake initiation accepted from the peer.
+    pub last_initiation: Option<Timestamp>,
+    pub last_seen: Option<Instant>,
+    pub rx_bytes: u64,
+    pub tx_bytes: u64,
+    pub rx_packets: u64,
+    pub tx_packets: u64,
+}
+
+/// All configured peers, indexed by public key, session index and tunnel IP.
+#[derive(Default)]
+pub struct PeerRegistry {
+    peers: HashMap<[u8; 32], Peer>,
+    by_index: HashMap<u32, [u8; 32]>,
+    by_tunnel_ip: HashMap<IpAddr, [u8; 32]>,
+}
+
+impl PeerRegistry {
+    /// Authorise `public_key` to use `tunnel_ips`.
+    pub fn add(&mut self, public_key: [u8; 32], tunnel_ips: Vec<IpAddr>) {
+        for ip in &tunnel_ips {
+            self.by_tunnel_ip.insert(*ip, public_key);
+        }
+        self.peers.insert(
+            public_key,
+            Peer {
+                tunnel_ips,
+                preshared_key: None,
+                keyring: Keyring::default(),
+                endpoint: None,
+                last_initiation: None,
+                last_seen: None,
+                rx_bytes: 0,
+                tx_bytes: 0,
+                rx_packets: 0,
+                tx_packets: 0,
+            },
+        );
+    }
+
+    /// Require `preshared_key` in handshakes from `public_key`.  Returns `false`
+    /// if `public_key` is not an authorised peer.
+    pub fn set_preshared_key(
+        &mut self,
+        public_key: &[u8; 32],
+        preshared_key: PresharedKey,
+    ) -> bool {
+        let Some(peer) = self.peers.get_mut(public_key) else {
+            return false;
+        };
+        peer.preshared_key = Some(preshared_key);
+        true
+    }
+
+    /// Pre-shared key for `public_key`, or `None` if it is not an authorised peer.
+    pub fn preshared_key(&self, public_key: &[u8; 32]) -> Option<[u8; 32]> {
+        let peer = self.peers.get(public_key)?;
+        Some(
+            peer.preshared_key
+                .as_ref()
+                .map_or(handshake::NO_PRESHARED_KEY, |psk| *psk.as_bytes()),
+        )
+    }
+
+    /// Attach a freshly negotiated session to the peer.  It becomes current once
+    /// the peer sends with it.  `timestamp` is the one carried by the
+    /// initiation, which must be newer than any accepted before.
+    pub fn establish(
+        &mut self,
+        public_key: &[u8; 32],
+        session: Session,
+        timestamp: Timestamp,
+    ) -> Result<(), Rejected> {
+        let Some(peer) = self.peers.get_mut(public_key) else {
+            return Err(Rejected::UnknownPeer);
+        };
+        if peer.last_initiation.is_some_and(|last| timestamp <= last) {
+            return Err(Rejected::Replayed);
+        }
+
+        self.by_index.insert(session.local_index(), *public_key);
+        peer.keyring.install_next(session);
+        peer.last_initiation = Some(timestamp);
+        peer.last_seen = Some(Instant::now());
+        Ok(())
+    }
+
+    /// Whether a session already uses our local `index`, so a new one must
+    /// not.
+    pub fn index_in_use(&self, index: u32) -> bool {
+        self.by_index.contains_key(&index)
+    }
+
+    /// Peer whose session uses our local `index`.
+    pub fn by_index_mut(&mut self, index: u32) -> Option<&mut Peer> {
+        let key = self.by_index.get(&index)?;
+        self.peers.get_mut(key)
+    }
+
+    /// Decrypt a transport message sent from `from` to our session `index`,
+    /// or return `None` if no session uses `index`.
+    ///
+    /// A packet that authenticates and passes the replay window shows where
+    /// the peer can be reached now, e.g. after moving from Wi-Fi to LTE, so
+    /// `from` becomes its endpoint.
+    pub fn receive(
+        &mut self,
+        index: u32,
+        counter: u64,
+        ciphertext: &[u8],
+        from: SocketAddr,
+        now: Instant,
+    ) -> Option<Result<Received<'_>, CryptoError>> {
+        let peer = self.by_index_mut(index)?;
+        let packet = match peer.keyring.open(index, counter, ciphertext, now)? {
+            Ok(packet) => packet,
+            Err(e) => return Some(Err(e)),
+        };
+
+        peer.last_seen = Some(now);
+        let roamed_from = peer
+            .endpoint
+            .replace(from)
+            .filter(|&previous| previous != from);
+        Some(Ok(Received {
+            peer,
+            packet,
+            roamed_from,
+        }))
+    }
+
+    /// Peer that owns tunnel address `ip`.
+    pub fn by_tunnel_ip_mut(&mut self, ip: &IpAddr) -> Option<&mut Peer> {
+        let key = self.by_tunnel_ip.get(ip)?;
+        self.peers.get_mut(key)
+    }
+
+    /// Retire expired sessions and forget indices no session uses any more.
+    pub fn expire(&mut self) {
+        let now = Instant::now();
+        for peer in self.peers.values_mut() {
+            peer.keyring.expire(now);
+        }
+
+        let peers = &self.peers;
+        self.by_index
+            .retain(|index, key| peers.get(key).is_some_and(|p| p.keyring.has_index(*index)));
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Peer> {
+        self.peers.values()
+    }
+}
+
+/// A transport message that [`PeerRegistry::receive`] decrypted.
+pub struct Received<'a> {
+    pub peer: &'a mut Peer,
+    pub packet: Vec<u8>,
+    /// Where the peer sent from before, if this packet moved its endpoint.
+    pub roamed_from: Option<SocketAddr>,
+}
+
+/// Why [`PeerRegistry::establish`] refused a session.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rejected {
+    UnknownPeer,
+    /// The initiation is not newer than one already accepted from the peer.
+    Replayed,
+}
+
+impl fmt::Display for Rejected {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Rejected::UnknownPeer => f.write_str("unknown peer"),
+            Rejected::Replayed => f.write_str("replayed initiation"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nysvpn_core::handshake::TransportKeys;
+    use nysvpn_core::wire::Message;
+    use std::net::Ipv4Addr;
+
+    const PEER: [u8; 32] = [7; 32];
+    const HOME: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(192, 0, 2, 10)), 40000);
+    const AWAY: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(198, 51, 100, 20)), 50000);
+
+    /// The server's end and the peer's end of a session with our index `index`.
+    fn pair(index: u32, now: Instant) -> (Session, Session) {
+        let (a, b) = ([index as u8; 32], [!index as u8; 32]);
+        let ours = TransportKeys {
+            local_index: index,
+            remote_index: index + 100,
+            send_key: a,
+            recv_key: b,
+        };
+        let theirs = TransportKeys {
+            local_index: index + 100,
+            remote_index: index,
+            send_key: b,
+            recv_key: a,
+        };
+        (Session::new(ours, now), Session::new(theirs, now))
+    }
+
+    /// A registry with one peer that has completed a handshake for session
+    /// `1`, and the peer's end of that session.
+    fn registry(now: Instant) -> (PeerRegistry, Session) {
+        let mut peers = PeerRegistry::default();
+        peers.add(
+            PEER,
+            vec!["10.8.0.2".parse().unwrap(), "fd00:8::2".parse().unwrap()],
+        );
+        let (ours, theirs) = pair(1, now);
+        peers.establish(&PEER, ours, [1; 12]).unwrap();
+        (peers, theirs)
+    }
+
+    /// Hand `datagram` to `peers` as if it arrived from `from`.
+    fn receive(
+        peers: &mut PeerRegistry,
+        datagram: &[u8],
+        from: SocketAddr,
+        now: Instant,
+    ) -> Option<Result<Vec<u8>, CryptoError>> {
+        let Some(Message::Transport {
+            receiver,
+            counter,
+            ciphertext,
+        }) = Message::parse(datagram)
+        else {
+            panic!("not a transport message");
+        };
+        let received = peers.receive(receiver, counter, ciphertext, from, now)?;
+        Some(received.map(|received| received.packet))
+    }
+
+    fn endpoint(peers: &mut PeerRegistry) -> Option<SocketAddr> {
+        peers
+            .by_tunnel_ip_mut(&"10.8.0.2".parse().unwrap())
+            .unwrap()
+            .endpoint
+    }
+
+    #[test]
+    fn authenticated_packets_move_the_endpoint() {
+        let now = Instant::now();
+        let (mut peers, mut theirs) = registry(now);
+        assert_eq!(endpoint(&mut peers), None);
+
+        let datagram = theirs.seal(b"hello", now).unwrap();
+        assert_eq!(
+            receive(&mut peers, &datagram, HOME, now),
+            Some(Ok(b"hello".to_vec()))
+        );
+        assert_eq!(endpoint(&mut peers), Some(HOME));
+
+        let datagram = theirs.seal(b"", now).unwrap();
+        let Message::Transport {
+            receiver,
+            counter,
+            ciphertext,
+        } = Message::parse(&datagram).unwrap()
+        else {
+            unreachable!();
+        };
+        let received = peers
+            .receive(receiver, counter, ciphertext, AWAY, now)
+            .unwrap()
+            .unwrap();
+        assert_eq!(received.roamed_from, Some(HOME));
+        assert_eq!(received.peer.last_seen, Some(now));
+        assert_eq!(endpoint(&mut peers), Some(AWAY));
+    }
+
+    #[test]
+    fn forged_packets_do_not_move_the_endpoint() {
+        let now = Instant::now();
+        let (mut peers, mut theirs) = registry(now);
+        let datagram = theirs.seal(b"hello", now).unwrap();
+        receive(&mut peers, &datagram, HOME, now).unwrap().unwrap();
+
+        let mut forged = theirs.seal(b"redirect", now).unwrap();
+        *forged.last_mut().unwrap() ^= 1;
+        assert_eq!(
+            receive(&mut peers, &forged, AWAY, now),
+            Some(Err(CryptoError::AuthFailed))
+        );
+        assert_eq!(endpoint(&mut peers), Some(HOME));
+    }
+
+    #[test]
+    fn replayed_packets_do_not_move_the_endpoint() {
+        let now = Instant::now();
+        let (mut peers, mut theirs) = registry(now);
+        let datagram = theirs.seal(b"hello", now).unwrap();
+        receive(&mut peers, &datagram, HOME, now).unwrap().unwrap();
+
+        assert!(matches!(
+            receive(&mut peers, &datagram, AWAY, now),
+            Some(Err(CryptoError::Replay(_)))
+        ));
+        assert_eq!(endpoint(&mut peers), Some(HOME));
+    }
+
+    #[test]
+    fn packets_without_a_session_do_not_move_the_endpoint() {
+        let now = Instant::now();
+        let mut peers = PeerRegistry::default();
+        peers.add(PEER, vec!["10.8.0.2".parse().unwrap()]);
+
+        // Sealed under a session the server never agreed to.
+        let (_, mut theirs) = pair(1, now);
+        let datagram = theirs.seal(b"hello", now).unwrap();
+        assert_eq!(receive(&mut peers, &datagram, AWAY, now), None);
+        assert_eq!(endpoint(&mut peers), None);
+    }
+
+    #[test]
+    fn initiations_must_be_newer_than_the_last() {
+        let now = Instant::now();
+        let (mut peers, _) = registry(now);
+
+        assert_eq!(
+            peers.establish(&PEER, pair(2, now).0, [1; 12]),
+            Err(Rejected::Replayed)
+        );
+        assert!(!peers.index_in_use(2));
+        assert_eq!(
+            peers.establish(&[8; 32], pair(3, now).0, [2; 12]),
+            Err(Rejected::UnknownPeer)
+        );
+        assert_eq!(peers.establish(&PEER, pair(4, now).0, [2; 12]), Ok(()));
+        assert!(peers.index_in_use(4));
+        // Handshakes alone do not reveal an endpoint.
+        assert_eq!(endpoint(&mut peers), None);
+    }
+
+    #[test]
+    fn packets_are_routed_by_tunnel_ip() {
+        let now = Instant::now();
+        let (mut peers, _) = registry(now);
+        peers.add([8; 32], vec!["10.8.0.3".parse().unwrap()]);
+
+        for (ip, owner) in [
+            ("10.8.0.2", "10.8.0.2"),
+            ("fd00:8::2", "10.8.0.2"),
+            ("10.8.0.3", "10.8.0.3"),
+        ] {
+            let peer = peers.by_tunnel_ip_mut(&ip.parse().unwrap()).unwrap();
+            assert_eq!(peer.tunnel_ips[0], owner.parse::<IpAddr>().unwrap(), "{ip}");
+        }
+        assert!(
+            peers
+                .by_tunnel_ip_mut(&"10.8.0.4".parse().unwrap())
+                .is_none()
+        );
+    }
+}
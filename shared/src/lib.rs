@@ -11,33 +11,153 @@ pub struct VpnConfig {
     pub server_public_key: String,
     /// Client's WireGuard private key (base64).
     pub client_private_key: String,
-    /// Assigned VPN tunnel IP for this client.
-    pub client_ip: IpAddr,
+    /// Assigned VPN tunnel addresses for this client, IPv4 and/or IPv6.
+    /// Older configs with a single `client_ip` are still accepted.
+    #[serde(alias = "client_ip", deserialize_with = "one_or_many")]
+    pub client_ips: Vec<IpAddr>,
+    /// Prefix length of the IPv4 tunnel network; defaults to 24.
+    #[serde(default)]
+    pub prefix_len: Option<u8>,
+    /// Prefix length of the IPv6 tunnel network; defaults to 64.
+    #[serde(default)]
+    pub prefix_len_v6: Option<u8>,
+    /// MTU of the tunnel interface; defaults to 1420.
+    #[serde(default)]
+    pub mtu: Option<u16>,
+    /// Name for the tunnel interface (e.g. `nysvpb0`, or `utun7` on macOS);
+    /// the OS picks one when unset.
+    #[serde(default)]
+    pub interface_name: Option<String>,
     /// DNS servers to use inside the tunnel.
     pub dns_servers: Vec<IpAddr>,
     /// CIDR ranges routed through the tunnel, e.g. ["0.0.0.0/0"].
     pub allowed_ips: Vec<String>,
+    /// Optional pre-shared key (base64) mixed into the handshake, as in
+    /// WireGuard's `PresharedKey`.  Must match the key configured for this
+    /// client on the server.
+    #[serde(default)]
+    pub preshared_key: Option<String>,
+    /// Longest wait, in seconds, between attempts to re-establish a lost
+    /// session; defaults to 60.
+    #[serde(default)]
+    pub reconnect_max_delay_secs: Option<u64>,
+    /// Send a keepalive after this many seconds without outgoing traffic, so
+    /// NAT mappings between us and the server stay open; off when unset.
+    #[serde(default)]
+    pub persistent_keepalive_secs: Option<u16>,
+}
+
+/// Accept either a single address or a list of them.
+fn one_or_many<'de, D>(deserializer: D) -> Result<Vec<IpAddr>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(IpAddr),
+        Many(Vec<IpAddr>),
+    }
+
+    Ok(match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(ip) => vec![ip],
+        OneOrMany::Many(ips) => ips,
+    })
+}
+
+/// Name of the tunnel clients use when the user does not pick one.
+pub const DEFAULT_TUNNEL: &str = "default";
+
+/// Newest IPC protocol version this build speaks.
+///
+/// Bump it whenever a change to the messages below would confuse a peer
+/// built before the change, and raise [`MIN_PROTOCOL_VERSION`] once the old
+/// form is no longer understood.
+///
+/// Version 2 added [`VpnCommand::GetKillSwitch`].
+pub const PROTOCOL_VERSION: u32 = 2;
+
+/// Oldest IPC protocol version this build still speaks.
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+
+/// A line sent by a client to the daemon.
+///
+/// A connection starts with a [`ClientMessage::Hello`]; every command after
+/// that is wrapped in a [`ClientMessage::Request`].
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum ClientMessage {
+    /// The protocol versions the client speaks, both ends inclusive.
+    Hello { min_version: u32, max_version: u32 },
+    /// A command, answered by a [`DaemonMessage::Response`] with the same `id`.
+    Request { id: u64, command: VpnCommand },
+}
+
+/// A line sent by the daemon to a client.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum DaemonMessage {
+    /// Accepts a [`ClientMessage::Hello`]; the connection uses `version` from
+    /// here on.
+    Welcome { version: u32 },
+    /// Rejects a [`ClientMessage::Hello`] with no version in common, giving
+    /// the versions the daemon speaks.  The daemon then hangs up.
+    Unsupported { min_version: u32, max_version: u32 },
+    /// The answer to the [`ClientMessage::Request`] with the same `id`.
+    Response { id: u64, response: VpnResponse },
+    /// Pushed after the response to [`VpnCommand::Subscribe`].
+    Event(VpnEvent),
+    /// A line that could not be matched to a request, such as one that is
+    /// not valid JSON or a request sent before the hello.
+    Error(String),
 }
 
 /// Commands sent from clients (CLI / GUI) to the daemon.
+///
+/// Tunnels are addressed by name; the daemon can run several at once.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum VpnCommand {
-    Connect(VpnConfig),
-    Disconnect,
-    GetStatus,
-    GetStats,
+    Connect {
+        name: String,
+        config: Box<VpnConfig>,
+    },
+    Disconnect {
+        name: String,
+    },
+    GetStatus {
+        name: String,
+    },
+    GetStats {
+        name: String,
+    },
+    /// Status and stats of every tunnel.
+    ListTunnels,
+    /// Keep the connection open and stream a [`DaemonMessage::Event`] per
+    /// line after the `Ok` response.  The connection takes no further
+    /// commands.
+    Subscribe,
+    /// Turn the kill switch on or off; it stays set until changed, across
+    /// daemon restarts.
+    SetKillSwitch(bool),
+    /// Whether the kill switch is on; answered with
+    /// [`VpnResponse::KillSwitch`].  Since protocol version 2.
+    GetKillSwitch,
 }
 
-/// Response envelope sent by the daemon.
+/// Result of a [`VpnCommand`].
+///
+/// Sent wrapped in a [`DaemonMessage::Response`], except that a client from
+/// before protocol versioning, which sends bare commands without a hello,
+/// gets a bare `Error` it can still read.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum VpnResponse {
     Ok,
     Status(TunnelStatus),
     Stats(TunnelStats),
+    Tunnels(Vec<TunnelInfo>),
+    KillSwitch(bool),
     Error(String),
 }
 
-/// Current state of the VPN tunnel.
+/// Current state of a VPN tunnel.
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 pub enum TunnelStatus {
     Disconnected,
@@ -48,15 +168,72 @@ pub enum TunnelStatus {
         /// Server address of the active connection.
         server: String,
     },
+    /// The session was lost and is being re-established.
+    Reconnecting {
+        server: String,
+        /// Attempts made so far, counting the one in progress.
+        #[serde(default)]
+        attempt: u32,
+        /// When the next attempt starts; `None` while one is in progress.
+        #[serde(default)]
+        next_attempt: Option<SystemTime>,
+    },
     Error(String),
 }
 
-/// Network transfer statistics for the active tunnel.
-#[derive(Debug, Clone, Serialize, Deserialize)]
+/// Network transfer statistics for a tunnel.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct TunnelStats {
     pub bytes_sent: u64,
     pub bytes_received: u64,
+    /// Datagrams sent to the server, keepalives aside.
+    #[serde(default)]
+    pub packets_sent: u64,
+    /// Datagrams received from the server that authenticated.
+    #[serde(default)]
+    pub packets_received: u64,
     pub last_handshake: Option<SystemTime>,
+    /// Round trip time of the latest handshake, in milliseconds.
+    #[serde(default)]
+    pub handshake_rtt_ms: Option<u32>,
+    /// Received packets dropped because they failed to authenticate or were malformed.
+    #[serde(default)]
+    pub decrypt_failures: u64,
+    /// Received packets dropped as duplicates or outside the replay window.
+    #[serde(default)]
+    pub replay_drops: u64,
+    /// Packets in either direction dropped for exceeding the tunnel MTU.
+    #[serde(default)]
+    pub mtu_drops: u64,
+    /// Send throughput in bytes per second, averaged over the last few seconds.
+    #[serde(default)]
+    pub bytes_sent_per_sec: u64,
+    /// Receive throughput in bytes per second, averaged over the last few seconds.
+    #[serde(default)]
+    pub bytes_received_per_sec: u64,
+}
+
+/// Pushed by the daemon to clients that sent [`VpnCommand::Subscribe`].
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum VpnEvent {
+    /// A tunnel's status changed.  Also sent for every tunnel when the
+    /// subscription starts.
+    StatusChanged { name: String, status: TunnelStatus },
+    /// A tunnel completed a handshake, on connect, reconnect or rekey.
+    HandshakeCompleted { name: String, rtt_ms: u32 },
+    /// A tunnel's statistics, sent about once a second while it exists.
+    Stats { name: String, stats: TunnelStats },
+    /// A problem the tunnel is trying to recover from, such as a lost
+    /// session; errors it gives up on arrive as [`TunnelStatus::Error`].
+    Error { name: String, message: String },
+}
+
+/// One tunnel as listed by [`VpnCommand::ListTunnels`].
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TunnelInfo {
+    pub name: String,
+    pub status: TunnelStatus,
+    pub stats: TunnelStats,
 }
 
 /// Metadata for a VPN server shown in the server-list UI.
@@ -79,4 +256,3 @@ pub struct ServerInfo {
 /// The `/tmp` path is used here as a cross-platform development default.
 /// In the installed LaunchDaemon the socket is created under `/var/run/`.
 pub const SOCKET_PATH: &str = "/tmp/nysvpb-daemon.sock";
-